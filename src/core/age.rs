@@ -0,0 +1,230 @@
+use std::str::FromStr;
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::core::{
+    entities::{BirthdayPrecision, Category, Dog},
+    error::Error,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LifeStage {
+    Puppy,
+    Adult,
+    Senior,
+}
+
+// 接口返回的计算字段, 不入库
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Age {
+    pub years: i32,
+    pub months: i32, // 不足一年的月数
+    pub life_stage: LifeStage,
+    pub human_years: f64,  // 相当于人类的年龄
+    pub approximate: bool, // 生日不精确到天时为true
+}
+
+// 成年和老年的起始月龄, 体型越大成年越晚, 衰老越早
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LifeStageThreshold {
+    pub adult_months: i32,
+    pub senior_months: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LifeStageThresholds {
+    pub small: LifeStageThreshold,
+    pub medium: LifeStageThreshold,
+    pub large: LifeStageThreshold,
+    pub giant: LifeStageThreshold,
+}
+
+impl Default for LifeStageThresholds {
+    fn default() -> Self {
+        Self {
+            small: LifeStageThreshold {
+                adult_months: 10,
+                senior_months: 120,
+            },
+            medium: LifeStageThreshold {
+                adult_months: 12,
+                senior_months: 96,
+            },
+            large: LifeStageThreshold {
+                adult_months: 15,
+                senior_months: 84,
+            },
+            giant: LifeStageThreshold {
+                adult_months: 18,
+                senior_months: 72,
+            },
+        }
+    }
+}
+
+impl LifeStageThresholds {
+    pub fn for_category(&self, category: &Category) -> LifeStageThreshold {
+        match category {
+            Category::Small => self.small,
+            Category::Medium => self.medium,
+            Category::Large => self.large,
+            Category::Giant => self.giant,
+        }
+    }
+
+    pub fn age(&self, dog: &Dog, today: NaiveDate) -> Age {
+        let months = dog.birthday.age_in_months(today).max(0);
+        let threshold = self.for_category(&dog.breed.category);
+        let life_stage = if months < threshold.adult_months {
+            LifeStage::Puppy
+        } else if months < threshold.senior_months {
+            LifeStage::Adult
+        } else {
+            LifeStage::Senior
+        };
+        Age {
+            years: months / 12,
+            months: months % 12,
+            life_stage,
+            human_years: human_years(months, &dog.breed.category),
+            approximate: dog.birthday.precision != BirthdayPrecision::Day,
+        }
+    }
+}
+
+// 格式: "Small=10/120,Medium=12/96,Large=15/84,Giant=18/72", 即体型=成年月龄/老年月龄, 未列出的体型使用默认值
+impl FromStr for LifeStageThresholds {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut thresholds = Self::default();
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let invalid = || Error::new("invalid life stage thresholds").with_cause(item.to_owned());
+            let (category, months) = item.split_once('=').ok_or_else(invalid)?;
+            let (adult, senior) = months.split_once('/').ok_or_else(invalid)?;
+            let threshold = LifeStageThreshold {
+                adult_months: adult.trim().parse().map_err(|_| invalid())?,
+                senior_months: senior.trim().parse().map_err(|_| invalid())?,
+            };
+            if threshold.adult_months >= threshold.senior_months {
+                return Err(invalid());
+            }
+            match category.trim() {
+                "Small" => thresholds.small = threshold,
+                "Medium" => thresholds.medium = threshold,
+                "Large" => thresholds.large = threshold,
+                "Giant" => thresholds.giant = threshold,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(thresholds)
+    }
+}
+
+// 第一年相当于人类15岁, 第二年再加9岁, 之后每年按体型增加4~7岁
+pub fn human_years(months: i32, category: &Category) -> f64 {
+    let years = months as f64 / 12.0;
+    let per_year = match category {
+        Category::Small => 4.0,
+        Category::Medium => 5.0,
+        Category::Large => 6.0,
+        Category::Giant => 7.0,
+    };
+    let human = if years <= 1.0 {
+        years * 15.0
+    } else if years <= 2.0 {
+        15.0 + (years - 1.0) * 9.0
+    } else {
+        24.0 + (years - 2.0) * per_year
+    };
+    (human * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn dog(category: &str, birthday: &str) -> Dog {
+        serde_json::from_value(json!({
+            "id": "6534c1e563e5adcdbf8a3790",
+            "name": "不二",
+            "gender": "Male",
+            "breed": { "id": "6534c1e563e5adcdbf8a3791", "category": category, "name": "柯基" },
+            "birthday": birthday,
+            "owner_id": "user-1",
+            "tags": [],
+            "portrait_id": null,
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": "2024-01-01T00:00:00Z",
+        }))
+        .unwrap()
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn human_years_grows_faster_for_larger_dogs_after_two_years() {
+        assert_eq!(human_years(0, &Category::Small), 0.0);
+        assert_eq!(human_years(6, &Category::Giant), 7.5);
+        assert_eq!(human_years(12, &Category::Medium), 15.0);
+        assert_eq!(human_years(18, &Category::Medium), 19.5);
+        assert_eq!(human_years(24, &Category::Large), 24.0);
+        assert_eq!(human_years(120, &Category::Small), 56.0);
+        assert_eq!(human_years(120, &Category::Giant), 80.0);
+    }
+
+    #[test]
+    fn thresholds_from_str_overrides_listed_categories() {
+        let thresholds = "Small=8/132, Giant=20/60".parse::<LifeStageThresholds>().unwrap();
+        assert_eq!(
+            thresholds.small,
+            LifeStageThreshold {
+                adult_months: 8,
+                senior_months: 132
+            }
+        );
+        assert_eq!(
+            thresholds.giant,
+            LifeStageThreshold {
+                adult_months: 20,
+                senior_months: 60
+            }
+        );
+        assert_eq!(thresholds.medium, LifeStageThresholds::default().medium);
+        assert_eq!("".parse::<LifeStageThresholds>().unwrap(), LifeStageThresholds::default());
+    }
+
+    #[test]
+    fn thresholds_from_str_rejects_invalid_items() {
+        for s in ["Small", "Small=10", "Small=a/120", "Small=120/10", "Tiny=10/120"] {
+            assert!(s.parse::<LifeStageThresholds>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn age_uses_category_thresholds() {
+        let thresholds = LifeStageThresholds::default();
+        let today = date(2024, 1, 1);
+        // 13个月的小型犬已成年, 大型犬还是幼犬
+        let small = thresholds.age(&dog("Small", "2022-12-01"), today);
+        let large = thresholds.age(&dog("Large", "2022-12-01"), today);
+        assert_eq!((small.years, small.months, small.life_stage), (1, 1, LifeStage::Adult));
+        assert_eq!(large.life_stage, LifeStage::Puppy);
+        assert!(!small.approximate);
+        assert_eq!(thresholds.age(&dog("Giant", "2017-06-01"), today).life_stage, LifeStage::Senior);
+    }
+
+    #[test]
+    fn age_of_imprecise_or_future_birthdays() {
+        let thresholds = LifeStageThresholds::default();
+        let age = thresholds.age(&dog("Medium", "2020"), date(2024, 1, 1));
+        assert_eq!((age.years, age.months, age.approximate), (3, 6, true));
+        let unborn = thresholds.age(&dog("Medium", "2024-06-01"), date(2024, 1, 1));
+        assert_eq!((unborn.years, unborn.months, unborn.life_stage), (0, 0, LifeStage::Puppy));
+    }
+}
@@ -0,0 +1,209 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::core::{
+    entities::{Breed, Dog},
+    repository::{DogCreate, Pagination},
+};
+
+// 没有用户参与的操作, 如命令行导入
+pub const SYSTEM_ACTOR: &str = "system";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditEntity {
+    Dog,
+    Breed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+    Restore,
+    Merge,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+// 审计日志只追加, 不修改
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    #[serde(default)]
+    pub id: String,
+    pub actor: String,
+    pub action: AuditAction,
+    pub entity: AuditEntity,
+    pub entity_id: String,
+    pub at: DateTime<Utc>,
+    pub changes: Vec<FieldChange>,
+}
+
+impl AuditEntry {
+    pub fn new(actor: &str, action: AuditAction, entity: AuditEntity, entity_id: &str, changes: Vec<FieldChange>) -> Self {
+        Self {
+            id: String::new(),
+            actor: actor.to_owned(),
+            action,
+            entity,
+            entity_id: entity_id.to_owned(),
+            at: Utc::now(),
+            changes,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AuditQuery {
+    pub entity: Option<AuditEntity>,
+    pub entity_id: Option<String>,
+    pub actor: Option<String>,
+    pub pagination: Option<Pagination>,
+}
+
+// 狗狗的可写字段, 用于比较修改前后的差异
+pub fn dog_snapshot(dog: &Dog) -> Value {
+    serde_json::to_value(DogCreate::from(dog)).unwrap_or_default()
+}
+
+// 品种去掉id, 版本号, 时间等非业务字段
+pub fn breed_snapshot(breed: &Breed) -> Value {
+    let mut value = serde_json::to_value(breed).unwrap_or_default();
+    if let Some(fields) = value.as_object_mut() {
+        for key in ["id", "version", "created_at", "updated_at", "category_label"] {
+            fields.remove(key);
+        }
+    }
+    value
+}
+
+// 按顶层字段比较, 创建时before为null, 删除时after为null
+pub fn diff(before: Option<&Value>, after: Option<&Value>) -> Vec<FieldChange> {
+    let empty = Map::new();
+    let before = before.and_then(Value::as_object).unwrap_or(&empty);
+    let after = after.and_then(Value::as_object).unwrap_or(&empty);
+    let mut fields = before.keys().chain(after.keys()).collect::<Vec<_>>();
+    fields.sort();
+    fields.dedup();
+    fields
+        .into_iter()
+        .filter_map(|field| {
+            let (b, a) = (before.get(field).unwrap_or(&Value::Null), after.get(field).unwrap_or(&Value::Null));
+            (b != a).then(|| FieldChange {
+                field: field.clone(),
+                before: b.clone(),
+                after: a.clone(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn dog(name: &str, version: u64, updated_at: &str) -> Dog {
+        serde_json::from_value(json!({
+            "id": "6534c1e563e5adcdbf8a3791",
+            "name": name,
+            "gender": "Male",
+            "breed": { "id": "6534c1e563e5adcdbf8a3790", "category": "Small", "name": "柯基" },
+            "birthday": "2022-01-01",
+            "owner_id": "user-1",
+            "tags": [],
+            "version": version,
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": updated_at,
+        }))
+        .unwrap()
+    }
+
+    fn breed(name: &str, version: u64) -> Breed {
+        serde_json::from_value(json!({
+            "id": "6534c1e563e5adcdbf8a3790",
+            "category": "Small",
+            "name": name,
+            "aliases": ["短腿"],
+            "group_id": null,
+            "parent_id": null,
+            "category_label": "小型犬",
+            "version": version,
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": "2024-01-02T00:00:00Z",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn diff_reports_changed_fields_only() {
+        let before = json!({ "name": "不二", "tags": ["乖"], "introduction": null });
+        let after = json!({ "name": "二二", "tags": ["乖"], "introduction": null });
+        let changes = diff(Some(&before), Some(&after));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            (changes[0].field.as_str(), &changes[0].before, &changes[0].after),
+            ("name", &json!("不二"), &json!("二二"))
+        );
+        assert!(diff(Some(&before), Some(&before)).is_empty());
+    }
+
+    #[test]
+    fn diff_treats_missing_fields_as_null() {
+        let before = json!({ "name": "不二", "portrait_id": "p1" });
+        let after = json!({ "name": "不二", "introduction": "很乖" });
+        let changes = diff(Some(&before), Some(&after))
+            .into_iter()
+            .map(|c| (c.field, c.before, c.after))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                ("introduction".to_owned(), Value::Null, json!("很乖")),
+                ("portrait_id".to_owned(), json!("p1"), Value::Null),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_of_create_and_delete_covers_all_fields() {
+        let value = json!({ "name": "不二", "tags": [] });
+        let created = diff(None, Some(&value));
+        assert_eq!(created.iter().map(|c| c.field.as_str()).collect::<Vec<_>>(), ["name", "tags"]);
+        assert!(created.iter().all(|c| c.before.is_null()));
+        let deleted = diff(Some(&value), None);
+        assert_eq!(deleted.iter().map(|c| c.field.as_str()).collect::<Vec<_>>(), ["name", "tags"]);
+        assert!(deleted.iter().all(|c| c.after.is_null()));
+    }
+
+    #[test]
+    fn dog_snapshot_ignores_version_and_timestamps() {
+        let before = dog("不二", 1, "2024-01-01T00:00:00Z");
+        let touched = dog("不二", 2, "2024-02-01T00:00:00Z");
+        assert!(diff(Some(&dog_snapshot(&before)), Some(&dog_snapshot(&touched))).is_empty());
+        let renamed = dog("二二", 3, "2024-03-01T00:00:00Z");
+        let changes = diff(Some(&dog_snapshot(&before)), Some(&dog_snapshot(&renamed)));
+        assert_eq!(changes.iter().map(|c| c.field.as_str()).collect::<Vec<_>>(), ["name"]);
+    }
+
+    #[test]
+    fn breed_snapshot_drops_non_business_fields() {
+        let snapshot = breed_snapshot(&breed("柯基", 1));
+        for key in ["id", "version", "created_at", "updated_at", "category_label"] {
+            assert!(snapshot.get(key).is_none(), "{} should be removed", key);
+        }
+        assert_eq!(snapshot["name"], json!("柯基"));
+        assert!(diff(Some(&snapshot), Some(&breed_snapshot(&breed("柯基", 2)))).is_empty());
+        let changes = diff(Some(&snapshot), Some(&breed_snapshot(&breed("威尔士柯基", 2))));
+        assert_eq!(changes.iter().map(|c| c.field.as_str()).collect::<Vec<_>>(), ["name"]);
+    }
+}
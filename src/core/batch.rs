@@ -0,0 +1,159 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::core::error::{Error, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchStatus {
+    Created,
+    Updated,
+    Unchanged, // 没有要修改的字段
+    Failed,
+    Skipped, // 全部成功才提交时, 因为其它项失败而没有执行
+}
+
+// 与HTTP接口的错误对应, code为错误类型, 不同于状态码, 每一项可以有不同的错误
+#[derive(Debug, Serialize)]
+pub struct BatchError {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+impl From<&Error> for BatchError {
+    fn from(e: &Error) -> Self {
+        let code = match e.kind() {
+            ErrorKind::InvalidInput => "invalid_input",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::PreconditionFailed => "precondition_failed",
+            ErrorKind::Internal => "internal",
+        };
+        Self {
+            code,
+            message: e.to_string(),
+            details: e.details().cloned(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchItemResult {
+    pub index: usize,
+    pub status: BatchStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<BatchError>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BatchReport {
+    pub atomic: bool,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub items: Vec<BatchItemResult>,
+}
+
+impl BatchReport {
+    pub fn new(atomic: bool) -> Self {
+        Self {
+            atomic,
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, index: usize, status: BatchStatus, id: Option<String>, version: Option<u64>) {
+        match status {
+            BatchStatus::Failed => self.failed += 1,
+            BatchStatus::Skipped => self.skipped += 1,
+            _ => self.succeeded += 1,
+        }
+        self.items.push(BatchItemResult {
+            index,
+            status,
+            id,
+            version,
+            error: None,
+        });
+    }
+
+    pub fn fail(&mut self, index: usize, id: Option<String>, error: &Error) {
+        self.failed += 1;
+        self.items.push(BatchItemResult {
+            index,
+            status: BatchStatus::Failed,
+            id,
+            version: None,
+            error: Some(error.into()),
+        });
+    }
+}
+
+// 请求体: {"atomic": true, "dogs": [...]}, atomic为true时全部成功才提交
+#[derive(Debug, Deserialize)]
+pub struct BatchReq {
+    #[serde(default)]
+    pub atomic: bool,
+    pub dogs: Vec<Value>,
+}
+
+// 请求中的一项, index从0开始, 解析失败的项也保留下来以便在结果中体现
+pub struct BatchItem<T> {
+    pub index: usize,
+    pub item: Result<T, Error>,
+}
+
+pub fn parse<T>(values: Vec<Value>) -> Vec<BatchItem<T>>
+where
+    T: DeserializeOwned,
+{
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(index, v)| BatchItem {
+            index,
+            item: serde_json::from_value::<T>(v).map_err(|e| Error::invalid_input("invalid batch item").with_cause(e)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Item {
+        name: String,
+    }
+
+    #[test]
+    fn parse_keeps_index_of_invalid_items() {
+        let items = parse::<Item>(vec![json!({ "name": "不二" }), json!({ "name": 1 }), json!({ "name": "大福" })]);
+        assert_eq!(items.iter().map(|i| i.index).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(items[0].item.as_ref().unwrap().name, "不二");
+        assert_eq!(items[1].item.as_ref().unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(items[2].item.as_ref().unwrap().name, "大福");
+    }
+
+    #[test]
+    fn report_counts_by_status() {
+        let mut report = BatchReport::new(false);
+        report.push(0, BatchStatus::Created, Some("dog-1".to_owned()), Some(1));
+        report.push(1, BatchStatus::Unchanged, Some("dog-2".to_owned()), Some(3));
+        report.push(2, BatchStatus::Skipped, None, None);
+        report.fail(3, None, &Error::conflict("version mismatch"));
+        assert_eq!((report.succeeded, report.skipped, report.failed), (2, 1, 1));
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(value["items"][3]["status"], "failed");
+        assert_eq!(value["items"][3]["error"]["code"], "conflict");
+        assert!(value["items"][2].get("id").is_none());
+    }
+}
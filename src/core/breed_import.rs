@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::{
+    entities::{BreedAttributes, Category, Level, Range},
+    error::Error,
+    repository::BreedCreate,
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub enum ImportStatus {
+    Created,
+    Updated,
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportRowResult {
+    pub row: usize,
+    pub name: String,
+    pub status: ImportStatus,
+    pub id: Option<String>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub rows: Vec<ImportRowResult>,
+}
+
+impl ImportReport {
+    pub fn push(&mut self, row: usize, name: &str, status: ImportStatus, id: Option<String>, message: Option<String>) {
+        match status {
+            ImportStatus::Created => self.created += 1,
+            ImportStatus::Updated => self.updated += 1,
+            ImportStatus::Skipped => self.skipped += 1,
+            ImportStatus::Failed => self.failed += 1,
+        }
+        self.rows.push(ImportRowResult {
+            row,
+            name: name.to_owned(),
+            status,
+            id,
+            message,
+        });
+    }
+}
+
+// 导入文件中的一行, row为行号, 解析失败的行也保留下来以便在报告中体现
+pub struct ImportRow {
+    pub row: usize,
+    pub name: String,
+    pub breed: Result<BreedCreate, Error>,
+}
+
+pub fn parse(data: &[u8], format: ImportFormat) -> Result<Vec<ImportRow>, Error> {
+    match format {
+        ImportFormat::Csv => parse_csv(data),
+        ImportFormat::Json => parse_json(data),
+    }
+}
+
+// JSON格式为BreedCreate数组, 与POST /apis/breeds的请求体一致
+fn parse_json(data: &[u8]) -> Result<Vec<ImportRow>, Error> {
+    let values =
+        serde_json::from_slice::<Vec<serde_json::Value>>(data).map_err(|e| Error::invalid_input("invalid json breed catalog").with_cause(e))?;
+    Ok(values
+        .into_iter()
+        .enumerate()
+        .map(|(i, v)| ImportRow {
+            row: i + 1,
+            name: v.get("name").and_then(|n| n.as_str()).unwrap_or_default().to_owned(),
+            breed: serde_json::from_value::<BreedCreate>(v).map_err(|e| Error::invalid_input("invalid breed").with_cause(e)),
+        })
+        .collect())
+}
+
+// CSV格式:
+// name,category,aliases,name_en,weight_kg_min,weight_kg_max,height_cm_min,height_cm_max,energy_level,
+// daily_exercise_minutes_min,daily_exercise_minutes_max,brachycephalic,heat_tolerance,cold_tolerance,shedding,
+// lifespan_years_min,lifespan_years_max,group_id
+// 别名用"|"分隔, name_<语言>列为对应语言的名字, 除name和category外的列都可以省略或留空
+fn parse_csv(data: &[u8]) -> Result<Vec<ImportRow>, Error> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data);
+    let headers = reader
+        .headers()
+        .map_err(|e| Error::invalid_input("invalid csv breed catalog").with_cause(e))?
+        .clone();
+    let mut rows = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        // 第一行是表头
+        let row = i + 2;
+        // 字段数不对等格式错误只影响这一行
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                rows.push(ImportRow {
+                    row,
+                    name: String::new(),
+                    breed: Err(Error::invalid_input("invalid csv row").with_cause(e)),
+                });
+                continue;
+            }
+        };
+        let fields = headers
+            .iter()
+            .zip(record.iter())
+            .filter(|(_, v)| !v.is_empty())
+            .collect::<HashMap<&str, &str>>();
+        rows.push(ImportRow {
+            row,
+            name: fields.get("name").copied().unwrap_or_default().to_owned(),
+            breed: csv_breed(&fields),
+        });
+    }
+    Ok(rows)
+}
+
+fn csv_breed(fields: &HashMap<&str, &str>) -> Result<BreedCreate, Error> {
+    let name = fields.get("name").ok_or(Error::invalid_input("name is required"))?;
+    let category = fields
+        .get("category")
+        .ok_or(Error::invalid_input("category is required"))
+        .and_then(|c| parse_category(c))?;
+    Ok(BreedCreate {
+        category,
+        name: name.to_string(),
+        names: fields
+            .iter()
+            .filter_map(|(k, v)| k.strip_prefix("name_").map(|locale| (locale.to_owned(), v.to_string())))
+            .collect(),
+        aliases: fields
+            .get("aliases")
+            .map(|a| a.split('|').map(str::trim).filter(|a| !a.is_empty()).map(str::to_owned).collect())
+            .unwrap_or_default(),
+        attributes: BreedAttributes {
+            weight_kg: parse_range(fields, "weight_kg")?,
+            height_cm: parse_range(fields, "height_cm")?,
+            energy_level: fields.get("energy_level").map(|l| parse_level(l)).transpose()?,
+            daily_exercise_minutes: parse_range(fields, "daily_exercise_minutes")?,
+            brachycephalic: fields.get("brachycephalic").map(|b| parse_bool(b)).transpose()?.unwrap_or_default(),
+            heat_tolerance: fields.get("heat_tolerance").map(|l| parse_level(l)).transpose()?,
+            cold_tolerance: fields.get("cold_tolerance").map(|l| parse_level(l)).transpose()?,
+            shedding: fields.get("shedding").map(|l| parse_level(l)).transpose()?,
+            lifespan_years: parse_range(fields, "lifespan_years")?,
+        },
+        group_id: fields.get("group_id").map(|g| g.to_string()),
+        parent_id: fields.get("parent_id").map(|p| p.to_string()),
+    })
+}
+
+fn parse_category(s: &str) -> Result<Category, Error> {
+    match s.to_lowercase().as_str() {
+        "small" | "小型犬" => Ok(Category::Small),
+        "medium" | "中型犬" => Ok(Category::Medium),
+        "large" | "大型犬" => Ok(Category::Large),
+        "giant" | "巨型犬" => Ok(Category::Giant),
+        _ => Err(Error::invalid_input("invalid category").with_cause(s.to_owned())),
+    }
+}
+
+fn parse_level(s: &str) -> Result<Level, Error> {
+    match s.to_lowercase().as_str() {
+        "low" | "低" => Ok(Level::Low),
+        "medium" | "中" => Ok(Level::Medium),
+        "high" | "高" => Ok(Level::High),
+        _ => Err(Error::invalid_input("invalid level").with_cause(s.to_owned())),
+    }
+}
+
+fn parse_bool(s: &str) -> Result<bool, Error> {
+    match s.to_lowercase().as_str() {
+        "true" | "yes" | "1" | "是" => Ok(true),
+        "false" | "no" | "0" | "否" => Ok(false),
+        _ => Err(Error::invalid_input("invalid boolean").with_cause(s.to_owned())),
+    }
+}
+
+fn parse_range<T>(fields: &HashMap<&str, &str>, prefix: &str) -> Result<Option<Range<T>>, Error>
+where
+    T: std::str::FromStr + Copy,
+    T::Err: std::fmt::Display + 'static,
+{
+    let parse = |suffix: &str| {
+        let key = format!("{}_{}", prefix, suffix);
+        fields
+            .get(key.as_str())
+            .map(|v| v.parse::<T>().map_err(|e| Error::invalid_input(format!("invalid {}", key)).with_cause(e)))
+            .transpose()
+    };
+    match (parse("min")?, parse("max")?) {
+        (Some(min), Some(max)) => Ok(Some(Range { min, max })),
+        (Some(v), None) | (None, Some(v)) => Ok(Some(Range { min: v, max: v })),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_rows_keep_line_numbers_and_optional_columns() {
+        let data = "name,category,aliases,name_en,weight_kg_min,weight_kg_max,energy_level,brachycephalic\n\
+                    金毛,大型犬,金毛寻回犬|黄金猎犬,Golden Retriever,25,34,高,否\n\
+                    柯基,small,,,,,,\n";
+        let rows = parse(data.as_bytes(), ImportFormat::Csv).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].row, rows[0].name.as_str()), (2, "金毛"));
+        let golden = rows[0].breed.as_ref().unwrap();
+        assert_eq!(golden.category, Category::Large);
+        assert_eq!(golden.aliases, ["金毛寻回犬", "黄金猎犬"]);
+        assert_eq!(golden.names.get("en").map(String::as_str), Some("Golden Retriever"));
+        assert_eq!(golden.attributes.weight_kg, Some(Range { min: 25.0, max: 34.0 }));
+        assert_eq!(golden.attributes.energy_level, Some(Level::High));
+        let corgi = rows[1].breed.as_ref().unwrap();
+        assert_eq!(corgi.category, Category::Small);
+        assert!(corgi.aliases.is_empty() && corgi.attributes == BreedAttributes::default());
+    }
+
+    #[test]
+    fn csv_single_value_range_is_min_and_max() {
+        let rows = parse("name,category,lifespan_years_max\n贵宾,medium,15\n".as_bytes(), ImportFormat::Csv).unwrap();
+        assert_eq!(
+            rows[0].breed.as_ref().unwrap().attributes.lifespan_years,
+            Some(Range { min: 15, max: 15 })
+        );
+    }
+
+    #[test]
+    fn csv_invalid_rows_fail_without_aborting_the_import() {
+        let data = "name,category,energy_level\n金毛,large\n柯基,small,extreme\n,small,\n贵宾,tiny,\n泰迪,small,low,extra\n哈士奇,medium,\n";
+        let rows = parse(data.as_bytes(), ImportFormat::Csv).unwrap();
+        let results = rows.iter().map(|r| (r.row, r.breed.is_ok())).collect::<Vec<_>>();
+        assert_eq!(results, [(2, false), (3, false), (4, false), (5, false), (6, false), (7, true)]);
+        assert_eq!(rows[5].name, "哈士奇");
+    }
+
+    #[test]
+    fn json_rows_keep_names_of_invalid_breeds() {
+        let data = r#"[{"name": "金毛", "category": "Large"}, {"name": "柯基", "category": "Tiny"}]"#;
+        let rows = parse(data.as_bytes(), ImportFormat::Json).unwrap();
+        assert!(rows[0].breed.is_ok());
+        assert_eq!((rows[1].row, rows[1].name.as_str(), rows[1].breed.is_err()), (2, "柯基", true));
+        assert!(parse(b"{}", ImportFormat::Json).is_err());
+    }
+}
@@ -1,8 +1,15 @@
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, Utc};
+use serde::{
+    de::{self, Unexpected},
+    Deserialize, Deserializer, Serialize,
+};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::core::{age::Age, error::Error, locale::Locale, search};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Category {
     Small,
     Medium,
@@ -25,24 +32,262 @@ impl Display for Category {
     }
 }
 
+impl Category {
+    pub fn label(&self, locale: Locale) -> &'static str {
+        match (self, locale) {
+            (Category::Small, Locale::Zh) => "小型犬",
+            (Category::Medium, Locale::Zh) => "中型犬",
+            (Category::Large, Locale::Zh) => "大型犬",
+            (Category::Giant, Locale::Zh) => "巨型犬",
+            (Category::Small, Locale::En) => "Small",
+            (Category::Medium, Locale::En) => "Medium",
+            (Category::Large, Locale::En) => "Large",
+            (Category::Giant, Locale::En) => "Giant",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Range<T> {
+    pub min: T,
+    pub max: T,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Level {
+    Low,
+    Medium,
+    High,
+}
+
+// 品种资料, 用于遛狗计划
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BreedAttributes {
+    pub weight_kg: Option<Range<f64>>,              // 体重
+    pub height_cm: Option<Range<f64>>,              // 肩高
+    pub energy_level: Option<Level>,                // 精力
+    pub daily_exercise_minutes: Option<Range<u32>>, // 每日运动时间
+    #[serde(default)]
+    pub brachycephalic: bool,  // 短头颅, 如法斗, 巴哥, 不耐热
+    pub heat_tolerance: Option<Level>,              // 耐热
+    pub cold_tolerance: Option<Level>,              // 耐寒
+    pub shedding: Option<Level>,                    // 掉毛
+    pub lifespan_years: Option<Range<u32>>,         // 寿命
+}
+
+// 品种分组, 如FCI的牧羊犬组, 㹴犬组, 视觉猎犬组
 #[derive(Debug, Serialize, Deserialize)]
+pub struct BreedGroup {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub names: HashMap<String, String>,
+    pub fci_number: Option<u8>, // FCI分组编号, 1~10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Breed {
     pub id: String,
     pub category: Category,
     pub name: String,
+    #[serde(default)]
+    pub names: HashMap<String, String>, // 各语言的名字, 如{"en": "Golden Retriever"}
+    #[serde(default)]
+    pub aliases: Vec<String>, // 别名, 如"金毛", "Golden Retriever"
+    #[serde(default)]
+    pub attributes: BreedAttributes,
+    pub group_id: Option<String>,  // 所属分组
+    pub parent_id: Option<String>, // 上级品种, 如玩具贵宾的上级为贵宾
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category_label: Option<String>,
+    #[serde(default)]
+    pub version: u64,
+    // 狗狗中保存的品种快照没有时间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl Breed {
+    // 并入重复品种: 名字和别名中没有的加入别名, 各语言的名字以自己的为准
+    pub fn absorb(&mut self, duplicate: &Breed) {
+        let mut keys = std::iter::once(&self.name)
+            .chain(&self.aliases)
+            .map(|n| search::normalize(n))
+            .collect::<HashSet<String>>();
+        for name in std::iter::once(&duplicate.name).chain(&duplicate.aliases) {
+            if keys.insert(search::normalize(name)) {
+                self.aliases.push(name.clone());
+            }
+        }
+        for (locale, name) in &duplicate.names {
+            self.names.entry(locale.clone()).or_insert_with(|| name.clone());
+        }
+    }
 }
 
 // 性别
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
 pub enum Gender {
+    #[default]
     Other,
     Male,
     Female,
 }
 
-impl Default for Gender {
-    fn default() -> Self {
-        Self::Other
+impl FromStr for Gender {
+    type Err = Error;
+
+    // 兼容常见写法和中文, 如"male", "M", "公", "雄"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "male" | "m" | "boy" | "公" | "雄" | "男" => Ok(Gender::Male),
+            "female" | "f" | "girl" | "母" | "雌" | "女" => Ok(Gender::Female),
+            "other" | "unknown" | "其他" | "未知" => Ok(Gender::Other),
+            _ => Err(Error::invalid_input("invalid gender").with_cause(s.to_owned())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Gender {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| de::Error::invalid_value(Unexpected::Str(&s), &"Male, Female or Other"))
+    }
+}
+
+impl Gender {
+    pub fn label(&self, locale: Locale) -> &'static str {
+        match (self, locale) {
+            (Gender::Other, Locale::Zh) => "其他",
+            (Gender::Male, Locale::Zh) => "公",
+            (Gender::Female, Locale::Zh) => "母",
+            (Gender::Other, Locale::En) => "Other",
+            (Gender::Male, Locale::En) => "Male",
+            (Gender::Female, Locale::En) => "Female",
+        }
+    }
+}
+
+// 混血犬的血统组成
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BreedComponent {
+    pub breed: Breed,
+    pub percentage: Option<u8>, // 占比, 未知时为空
+}
+
+// 生日的精确程度, 救助犬通常只知道大概的年份或年月
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BirthdayPrecision {
+    Day,
+    Month,
+    Year,
+    Estimated, // 估计值, 前后误差一年
+}
+
+// 生日只保存日期, 与时区无关
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Birthday {
+    pub date: NaiveDate,
+    pub precision: BirthdayPrecision,
+}
+
+impl Birthday {
+    // 按精度对齐日期, 精确到月时为当月1日, 精确到年时为当年1月1日
+    pub fn new(date: NaiveDate, precision: BirthdayPrecision) -> Self {
+        let date = match precision {
+            BirthdayPrecision::Month => date.with_day(1).unwrap_or(date),
+            BirthdayPrecision::Year => NaiveDate::from_ymd_opt(date.year(), 1, 1).unwrap_or(date),
+            BirthdayPrecision::Day | BirthdayPrecision::Estimated => date,
+        };
+        Self { date, precision }
+    }
+
+    // 可能的最早日期
+    pub fn earliest(&self) -> NaiveDate {
+        match self.precision {
+            BirthdayPrecision::Estimated => self.date.checked_sub_months(Months::new(12)).unwrap_or(self.date),
+            _ => self.date,
+        }
+    }
+
+    // 可能的最晚日期
+    pub fn latest(&self) -> NaiveDate {
+        let next = match self.precision {
+            BirthdayPrecision::Day => return self.date,
+            BirthdayPrecision::Month => self.date.checked_add_months(Months::new(1)),
+            BirthdayPrecision::Year => self.date.checked_add_months(Months::new(12)),
+            BirthdayPrecision::Estimated => return self.date.checked_add_months(Months::new(12)).unwrap_or(self.date),
+        };
+        next.and_then(|d| d.pred_opt()).unwrap_or(self.date)
+    }
+
+    // 计算年龄时使用可能范围的中点
+    pub fn midpoint(&self) -> NaiveDate {
+        let earliest = self.earliest();
+        earliest + (self.latest() - earliest) / 2
+    }
+
+    // 到today为止的整月数
+    pub fn age_in_months(&self, today: NaiveDate) -> i32 {
+        let born = self.midpoint();
+        let months = (today.year() - born.year()) * 12 + today.month() as i32 - born.month() as i32;
+        if today.day() < born.day() {
+            months - 1
+        } else {
+            months
+        }
+    }
+}
+
+impl FromStr for Birthday {
+    type Err = Error;
+
+    // "2022-01-01", "2022-01", "2022"或RFC 3339时间
+    // 带时区的时间取其所在时区的日期, UTC时间(旧数据)按最近的零点取日期, 以免东八区的零点被记成前一天
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok(Birthday::new(date, BirthdayPrecision::Day));
+        }
+        if let Ok(date) = NaiveDate::parse_from_str(&format!("{}-01", s), "%Y-%m-%d") {
+            return Ok(Birthday::new(date, BirthdayPrecision::Month));
+        }
+        if let Some(date) = s.parse::<i32>().ok().and_then(|y| NaiveDate::from_ymd_opt(y, 1, 1)) {
+            return Ok(Birthday::new(date, BirthdayPrecision::Year));
+        }
+        let datetime = DateTime::parse_from_rfc3339(s).map_err(|e| Error::invalid_input("invalid birthday").with_cause(e))?;
+        let date = if datetime.offset().local_minus_utc() == 0 {
+            (datetime.naive_utc() + Duration::hours(12)).date()
+        } else {
+            datetime.date_naive()
+        };
+        Ok(Birthday::new(date, BirthdayPrecision::Day))
+    }
+}
+
+impl<'de> Deserialize<'de> for Birthday {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Full { date: NaiveDate, precision: BirthdayPrecision },
+            Text(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Full { date, precision } => Ok(Birthday::new(date, precision)),
+            Repr::Text(s) => s
+                .parse()
+                .map_err(|_| de::Error::invalid_value(Unexpected::Str(&s), &"a date such as 2022-01-01, 2022-01 or 2022")),
+        }
     }
 }
 
@@ -52,11 +297,207 @@ pub struct Dog {
     pub id: String,
     pub name: String,
     pub gender: Gender,
-    pub breed: Breed,            // 品种
-    pub birthday: DateTime<Utc>, // 生日
-    // pub is_sterilized: bool,     // 是否绝育
-    // pub introduction: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gender_label: Option<String>,
+    pub breed: Breed, // 品种
+    #[serde(default)]
+    pub breed_components: Vec<BreedComponent>, // 混血组成
+    #[serde(default)]
+    pub unknown_mix: bool, // 血统不明的混血
+    pub birthday: Birthday, // 生日
+    #[serde(default)]
+    pub is_sterilized: bool, // 是否绝育
+    #[serde(default)]
+    pub introduction: String, // 简介
     pub owner_id: String,
     pub tags: Vec<String>,
     pub portrait_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age: Option<Age>, // 根据生日计算, 不入库
+    #[serde(default)]
+    pub version: u64, // 每次修改加1, 用作ETag
+    #[serde(default, skip_serializing)]
+    pub seq: u64, // 变更序号, 用于增量同步
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// 已删除或已转让给其他主人的狗狗, 用于增量同步
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DogTombstone {
+    pub id: String,
+    pub deleted_at: DateTime<Utc>,
+    #[serde(default, skip_serializing)]
+    pub seq: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breed(name: &str, aliases: &[&str], names: &[(&str, &str)]) -> Breed {
+        Breed {
+            id: String::new(),
+            category: Category::Small,
+            name: name.to_owned(),
+            names: names.iter().map(|(l, n)| (l.to_string(), n.to_string())).collect(),
+            aliases: aliases.iter().map(|a| a.to_string()).collect(),
+            attributes: Default::default(),
+            group_id: None,
+            parent_id: None,
+            category_label: None,
+            version: 1,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn gender_from_str_accepts_aliases() {
+        let cases = [
+            ("Male", Gender::Male),
+            (" m ", Gender::Male),
+            ("boy", Gender::Male),
+            ("公", Gender::Male),
+            ("雄", Gender::Male),
+            ("男", Gender::Male),
+            ("FEMALE", Gender::Female),
+            ("F", Gender::Female),
+            ("girl", Gender::Female),
+            ("母", Gender::Female),
+            ("雌", Gender::Female),
+            ("女", Gender::Female),
+            ("other", Gender::Other),
+            ("Unknown", Gender::Other),
+            ("其他", Gender::Other),
+            ("未知", Gender::Other),
+        ];
+        for (s, gender) in cases {
+            assert_eq!(s.parse::<Gender>().unwrap(), gender, "{}", s);
+        }
+        for s in ["", "x", "公母"] {
+            assert!(s.parse::<Gender>().is_err(), "{}", s);
+        }
+        assert_eq!(serde_json::from_str::<Gender>("\"母\"").unwrap(), Gender::Female);
+        assert_eq!(Gender::default(), Gender::Other);
+    }
+
+    #[test]
+    fn absorb_adds_missing_names_as_aliases() {
+        let mut target = breed("柯基", &["Corgi"], &[("en", "Welsh Corgi")]);
+        target.absorb(&breed("威尔士柯基", &["corgi", "短腿"], &[("en", "Corgi"), ("ja", "コーギー")]));
+        assert_eq!(target.name, "柯基");
+        assert_eq!(target.aliases, ["Corgi", "威尔士柯基", "短腿"]);
+        assert_eq!(target.names["en"], "Welsh Corgi");
+        assert_eq!(target.names["ja"], "コーギー");
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn birthday_from_str_infers_precision() {
+        assert_eq!(
+            "2022-03-15".parse::<Birthday>().unwrap(),
+            Birthday {
+                date: date(2022, 3, 15),
+                precision: BirthdayPrecision::Day
+            }
+        );
+        assert_eq!(
+            " 2022-03 ".parse::<Birthday>().unwrap(),
+            Birthday {
+                date: date(2022, 3, 1),
+                precision: BirthdayPrecision::Month
+            }
+        );
+        assert_eq!(
+            "2022".parse::<Birthday>().unwrap(),
+            Birthday {
+                date: date(2022, 1, 1),
+                precision: BirthdayPrecision::Year
+            }
+        );
+        assert!("2022-13".parse::<Birthday>().is_err());
+        assert!("yesterday".parse::<Birthday>().is_err());
+    }
+
+    #[test]
+    fn birthday_from_rfc3339_keeps_the_local_date() {
+        assert_eq!("2022-01-01T00:00:00+08:00".parse::<Birthday>().unwrap().date, date(2022, 1, 1));
+        // 旧数据把东八区的零点保存为前一天16点的UTC时间
+        assert_eq!("2021-12-31T16:00:00Z".parse::<Birthday>().unwrap().date, date(2022, 1, 1));
+        assert_eq!("2022-01-01T00:00:00Z".parse::<Birthday>().unwrap().date, date(2022, 1, 1));
+    }
+
+    #[test]
+    fn birthday_new_aligns_date_to_precision() {
+        assert_eq!(Birthday::new(date(2022, 3, 15), BirthdayPrecision::Month).date, date(2022, 3, 1));
+        assert_eq!(Birthday::new(date(2022, 3, 15), BirthdayPrecision::Year).date, date(2022, 1, 1));
+        assert_eq!(Birthday::new(date(2022, 3, 15), BirthdayPrecision::Estimated).date, date(2022, 3, 15));
+    }
+
+    #[test]
+    fn birthday_range_and_midpoint() {
+        let cases = [
+            (
+                Birthday::new(date(2022, 3, 15), BirthdayPrecision::Day),
+                date(2022, 3, 15),
+                date(2022, 3, 15),
+                date(2022, 3, 15),
+            ),
+            (
+                Birthday::new(date(2024, 2, 10), BirthdayPrecision::Month),
+                date(2024, 2, 1),
+                date(2024, 2, 29),
+                date(2024, 2, 15),
+            ),
+            (
+                Birthday::new(date(2022, 6, 1), BirthdayPrecision::Year),
+                date(2022, 1, 1),
+                date(2022, 12, 31),
+                date(2022, 7, 2),
+            ),
+            (
+                Birthday::new(date(2022, 6, 1), BirthdayPrecision::Estimated),
+                date(2021, 6, 1),
+                date(2023, 6, 1),
+                date(2022, 6, 1),
+            ),
+        ];
+        for (birthday, earliest, latest, midpoint) in cases {
+            assert_eq!(
+                (birthday.earliest(), birthday.latest(), birthday.midpoint()),
+                (earliest, latest, midpoint),
+                "{:?}",
+                birthday
+            );
+        }
+    }
+
+    #[test]
+    fn birthday_age_in_months_counts_whole_months() {
+        let birthday = Birthday::new(date(2022, 3, 15), BirthdayPrecision::Day);
+        assert_eq!(birthday.age_in_months(date(2022, 3, 15)), 0);
+        assert_eq!(birthday.age_in_months(date(2023, 3, 14)), 11);
+        assert_eq!(birthday.age_in_months(date(2023, 3, 15)), 12);
+    }
+
+    #[test]
+    fn birthday_deserializes_from_text_or_stored_document() {
+        let text = serde_json::from_str::<Birthday>(r#""2022-03""#).unwrap();
+        assert_eq!(text.precision, BirthdayPrecision::Month);
+        let stored =
+            serde_json::from_str::<Birthday>(r#"{"date": "2022-03-15", "precision": "Year", "earliest": "2022-01-01", "latest": "2022-12-31"}"#)
+                .unwrap();
+        assert_eq!(
+            stored,
+            Birthday {
+                date: date(2022, 1, 1),
+                precision: BirthdayPrecision::Year
+            }
+        );
+        assert!(serde_json::from_str::<Birthday>(r#""someday""#).is_err());
+    }
 }
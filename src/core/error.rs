@@ -1,8 +1,22 @@
 use std::fmt::{Debug, Display};
 
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorKind {
+    #[default]
+    Internal,
+    InvalidInput,
+    Conflict,
+    NotFound,
+    PreconditionFailed,
+}
+
 pub struct Error {
+    kind: ErrorKind,
     message: String,
     cause: Option<Box<dyn Display>>,
+    details: Option<Value>,
 }
 
 impl Display for Error {
@@ -28,7 +42,68 @@ impl Error {
     where
         S: Into<String>,
     {
-        Self { message: message.into(), cause: None }
+        Self {
+            kind: ErrorKind::Internal,
+            message: message.into(),
+            cause: None,
+            details: None,
+        }
+    }
+
+    pub fn invalid_input<S>(message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            kind: ErrorKind::InvalidInput,
+            ..Self::new(message)
+        }
+    }
+
+    pub fn conflict<S>(message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            kind: ErrorKind::Conflict,
+            ..Self::new(message)
+        }
+    }
+
+    pub fn not_found<S>(message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            kind: ErrorKind::NotFound,
+            ..Self::new(message)
+        }
+    }
+
+    pub fn precondition_failed<S>(message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            kind: ErrorKind::PreconditionFailed,
+            ..Self::new(message)
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    // 返回给客户端的结构化信息, 如冲突记录的id
+    pub fn details(&self) -> Option<&Value> {
+        self.details.as_ref()
+    }
+
+    pub fn with_details(self, details: Value) -> Self {
+        Self {
+            details: Some(details),
+            ..self
+        }
     }
 
     pub fn with_cause(self, cause: impl Display + 'static) -> Self {
@@ -0,0 +1,233 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Stdout, Write},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use futures::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use crate::core::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EventType {
+    DogCreated,
+    DogUpdated,
+    DogTransferred,
+    DogDeleted,
+    DogRestored,
+    BreedChanged,
+}
+
+// 领域事件与修改在同一个事务中写入发件箱, 由Dispatcher投递
+// 投递保证至少一次, 消费方按id去重, 同一个狗狗或品种的事件按version排序
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DomainEvent {
+    #[serde(default)]
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: EventType,
+    pub aggregate_id: String, // 狗狗或品种的id
+    pub owner_id: Option<String>,
+    pub version: u64, // 修改后的版本号
+    pub occurred_at: DateTime<Utc>,
+    pub data: Value,
+}
+
+impl DomainEvent {
+    pub fn new(event_type: EventType, aggregate_id: &str, owner_id: Option<&str>, version: u64, data: Value) -> Self {
+        Self {
+            id: String::new(),
+            event_type,
+            aggregate_id: aggregate_id.to_owned(),
+            owner_id: owner_id.map(str::to_owned),
+            version,
+            occurred_at: Utc::now(),
+            data,
+        }
+    }
+}
+
+// 需要以trait对象保存多个sink, 所以返回LocalBoxFuture而不是async fn, Dispatcher只在本线程的任务中运行
+pub trait EventSink {
+    fn publish<'a>(&'a self, events: &'a [DomainEvent]) -> LocalBoxFuture<'a, Result<(), Error>>;
+}
+
+// 进程内广播, 订阅者处理不及时会丢失最早的事件(Lagged)
+#[derive(Clone)]
+pub struct InProcessSink {
+    sender: broadcast::Sender<DomainEvent>,
+}
+
+impl InProcessSink {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DomainEvent> {
+        self.sender.subscribe()
+    }
+}
+
+impl EventSink for InProcessSink {
+    fn publish<'a>(&'a self, events: &'a [DomainEvent]) -> LocalBoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            for event in events {
+                // 没有订阅者时send返回错误, 忽略
+                let _ = self.sender.send(event.clone());
+            }
+            Ok(())
+        })
+    }
+}
+
+// 每个事件一行JSON, 可以写到标准输出, 文件, 测试时也可以写到Vec<u8>
+pub struct WriterSink<W> {
+    writer: Mutex<W>,
+}
+
+impl<W> WriterSink<W>
+where
+    W: Write,
+{
+    pub fn new(writer: W) -> Self {
+        Self { writer: Mutex::new(writer) }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl WriterSink<Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(std::io::stdout())
+    }
+}
+
+impl WriterSink<File> {
+    pub fn file(path: &str) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::new("failed to open event file").with_cause(e))?;
+        Ok(Self::new(file))
+    }
+}
+
+impl<W> EventSink for WriterSink<W>
+where
+    W: Write,
+{
+    fn publish<'a>(&'a self, events: &'a [DomainEvent]) -> LocalBoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+            for event in events {
+                let line = serde_json::to_string(event).map_err(|e| Error::new("failed to write event").with_cause(e))?;
+                writeln!(writer, "{}", line).map_err(|e| Error::new("failed to write event").with_cause(e))?;
+            }
+            writer.flush().map_err(|e| Error::new("failed to write event").with_cause(e))
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Dispatcher {
+    sinks: Vec<Box<dyn EventSink>>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sink(mut self, sink: impl EventSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    // 任一sink失败时整批事件都不会标记为已投递, 下次重新投递, 其它sink会收到重复的事件
+    pub async fn publish(&self, events: &[DomainEvent]) -> Result<(), Error> {
+        for sink in &self.sinks {
+            sink.publish(events).await?;
+        }
+        Ok(())
+    }
+}
+
+// 格式: "stdout,file:/var/log/dog-events.jsonl", 为空时只投递到进程内
+impl FromStr for Dispatcher {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut dispatcher = Self::new();
+        for sink in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            dispatcher = match sink.split_once(':') {
+                None if sink == "stdout" => dispatcher.with_sink(WriterSink::stdout()),
+                Some(("file", path)) => dispatcher.with_sink(WriterSink::file(path)?),
+                _ => return Err(Error::new("invalid event sink").with_cause(sink.to_owned())),
+            };
+        }
+        Ok(dispatcher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    use super::*;
+
+    fn event(version: u64) -> DomainEvent {
+        DomainEvent::new(EventType::DogUpdated, "dog-1", Some("user-1"), version, json!({ "name": "不二" }))
+    }
+
+    #[test]
+    fn writer_sink_writes_one_line_per_event() {
+        let sink = WriterSink::new(Vec::new());
+        block_on(sink.publish(&[event(1), event(2)])).unwrap();
+        let out = String::from_utf8(sink.into_inner()).unwrap();
+        let versions = out
+            .lines()
+            .map(|l| serde_json::from_str::<DomainEvent>(l).unwrap().version)
+            .collect::<Vec<_>>();
+        assert_eq!(versions, vec![1, 2]);
+        assert!(out.contains(r#""type":"DogUpdated""#));
+    }
+
+    #[test]
+    fn in_process_sink_broadcasts_to_subscribers() {
+        let sink = InProcessSink::new(4);
+        let mut rx = sink.subscribe();
+        block_on(sink.publish(&[event(3)])).unwrap();
+        assert_eq!(rx.try_recv().unwrap().version, 3);
+    }
+
+    #[test]
+    fn dispatcher_from_str_parses_sinks() {
+        assert!("".parse::<Dispatcher>().unwrap().sinks.is_empty());
+        assert_eq!(" stdout , ".parse::<Dispatcher>().unwrap().sinks.len(), 1);
+        assert!("kafka:dogs".parse::<Dispatcher>().is_err());
+        assert!("stderr".parse::<Dispatcher>().is_err());
+    }
+
+    #[test]
+    fn dispatcher_publishes_to_file_sink() {
+        let path = std::env::temp_dir().join(format!("dog-events-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let dispatcher = format!("file:{}", path.display()).parse::<Dispatcher>().unwrap();
+        block_on(dispatcher.publish(&[event(1)])).unwrap();
+        block_on(dispatcher.publish(&[event(2)])).unwrap();
+        let out = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(out.lines().count(), 2);
+    }
+}
@@ -0,0 +1,101 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::core::events::{DomainEvent, EventType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DogChange {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl DogChange {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DogChange::Created => "created",
+            DogChange::Updated => "updated",
+            DogChange::Deleted => "deleted",
+        }
+    }
+}
+
+// 推送给主人的变化通知, 只包含id和版本号, 客户端按需重新获取狗狗
+#[derive(Debug, Clone, Serialize)]
+pub struct DogNotification {
+    pub event_id: String,
+    pub change: DogChange,
+    pub dog_id: String,
+    pub version: u64,
+    pub occurred_at: DateTime<Utc>,
+}
+
+impl DogNotification {
+    // 用户能看到的狗狗即自己的狗狗, 转让对新主人是创建, 对原主人是删除
+    pub fn for_user(event: &DomainEvent, user_id: &str) -> Option<Self> {
+        let owner = event.owner_id.as_deref();
+        let change = match event.event_type {
+            EventType::DogCreated | EventType::DogRestored if owner == Some(user_id) => DogChange::Created,
+            EventType::DogUpdated if owner == Some(user_id) => DogChange::Updated,
+            EventType::DogDeleted if owner == Some(user_id) => DogChange::Deleted,
+            EventType::DogTransferred if owner == Some(user_id) => DogChange::Created,
+            EventType::DogTransferred if event.data.get("from").and_then(|f| f.as_str()) == Some(user_id) => DogChange::Deleted,
+            _ => return None,
+        };
+        Some(Self {
+            event_id: event.id.clone(),
+            change,
+            dog_id: event.aggregate_id.clone(),
+            version: event.version,
+            occurred_at: event.occurred_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn event(event_type: EventType, owner_id: &str, data: Value) -> DomainEvent {
+        let mut event = DomainEvent::new(event_type, "dog-1", Some(owner_id), 3, data);
+        event.id = "event-1".to_owned();
+        event
+    }
+
+    fn change(event: &DomainEvent, user_id: &str) -> Option<DogChange> {
+        DogNotification::for_user(event, user_id).map(|n| n.change)
+    }
+
+    #[test]
+    fn for_user_notifies_owner_only() {
+        for (event_type, expected) in [
+            (EventType::DogCreated, DogChange::Created),
+            (EventType::DogRestored, DogChange::Created),
+            (EventType::DogUpdated, DogChange::Updated),
+            (EventType::DogDeleted, DogChange::Deleted),
+        ] {
+            let e = event(event_type, "user-1", json!({}));
+            assert_eq!(change(&e, "user-1"), Some(expected));
+            assert_eq!(change(&e, "user-2"), None);
+        }
+        let n = DogNotification::for_user(&event(EventType::DogUpdated, "user-1", json!({})), "user-1").unwrap();
+        assert_eq!((n.event_id.as_str(), n.dog_id.as_str(), n.version), ("event-1", "dog-1", 3));
+    }
+
+    #[test]
+    fn for_user_splits_transfer_between_owners() {
+        let e = event(EventType::DogTransferred, "user-2", json!({ "from": "user-1" }));
+        assert_eq!(change(&e, "user-2"), Some(DogChange::Created));
+        assert_eq!(change(&e, "user-1"), Some(DogChange::Deleted));
+        assert_eq!(change(&e, "user-3"), None);
+    }
+
+    #[test]
+    fn for_user_ignores_breed_events() {
+        let e = event(EventType::BreedChanged, "user-1", json!({}));
+        assert_eq!(change(&e, "user-1"), None);
+    }
+}
@@ -0,0 +1,79 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const MAX_KEY_LEN: usize = 255;
+
+// 第一次请求的响应, 重试时原样返回
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+// 每个(用户, Idempotency-Key)一条记录, response为None表示第一次请求还在处理中
+// 处理中的记录只锁定到locked_until, 进程崩溃或请求中断没有释放时, 之后的重试可以重新占用
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    pub user_id: String,
+    pub key: String,
+    pub fingerprint: String,
+    pub response: Option<StoredResponse>,
+    pub created_at: DateTime<Utc>,
+    pub locked_until: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl IdempotencyRecord {
+    pub fn new(user_id: &str, key: &str, fingerprint: String, lease: Duration, window: Duration) -> Self {
+        let now = Utc::now();
+        Self {
+            user_id: user_id.to_owned(),
+            key: key.to_owned(),
+            fingerprint,
+            response: None,
+            created_at: now,
+            locked_until: now + lease,
+            expires_at: now + window,
+        }
+    }
+}
+
+// 同一个key只能用于同一个请求, 用方法, 路径和请求体的摘要判断
+pub fn fingerprint(method: &str, path: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update(b" ");
+    hasher.update(path.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+pub enum Idempotency {
+    New,                    // 第一次请求, 已经占用了key
+    Replay(StoredResponse), // 重试, 返回第一次的响应
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_is_locked_for_lease_and_kept_for_window() {
+        let record = IdempotencyRecord::new("user-1", "key-1", String::new(), Duration::minutes(5), Duration::hours(24));
+        assert_eq!(record.locked_until - record.created_at, Duration::minutes(5));
+        assert_eq!(record.expires_at - record.created_at, Duration::hours(24));
+        assert!(record.response.is_none());
+    }
+
+    #[test]
+    fn fingerprint_covers_method_path_and_body() {
+        let fp = fingerprint("POST", "/apis/dogs", b"{}");
+        assert_eq!(fp, fingerprint("POST", "/apis/dogs", b"{}"));
+        assert_ne!(fp, fingerprint("PATCH", "/apis/dogs", b"{}"));
+        assert_ne!(fp, fingerprint("POST", "/apis/dogs/1", b"{}"));
+        assert_ne!(fp, fingerprint("POST", "/apis/dogs", b"{\"name\":\"a\"}"));
+    }
+}
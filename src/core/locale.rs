@@ -0,0 +1,121 @@
+use std::str::FromStr;
+
+use crate::core::{
+    entities::{Breed, BreedGroup, Dog},
+    error::Error,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    Zh,
+    En,
+}
+
+impl Locale {
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Locale::Zh => "zh",
+            Locale::En => "en",
+        }
+    }
+
+    // 按Accept-Language中的q值选出支持的语言, 都不支持时返回None
+    pub fn from_accept_language(header: &str) -> Option<Self> {
+        let mut candidates = header
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let locale = parts.next()?.trim().parse::<Locale>().ok()?;
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+                    .unwrap_or(1.0);
+                Some((locale, q))
+            })
+            .filter(|(_, q)| *q > 0.0)
+            .collect::<Vec<_>>();
+        candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        candidates.first().map(|(l, _)| *l)
+    }
+}
+
+impl FromStr for Locale {
+    type Err = Error;
+
+    // 只比较主语言, "zh-CN", "zh-Hans" => Zh
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let primary = s.split(['-', '_']).next().unwrap_or_default().to_lowercase();
+        match primary.as_str() {
+            "zh" => Ok(Locale::Zh),
+            "en" => Ok(Locale::En),
+            _ => Err(Error::new("unsupported locale").with_cause(s.to_owned())),
+        }
+    }
+}
+
+pub trait Localize {
+    fn localize(&mut self, locale: Locale);
+}
+
+impl Localize for Breed {
+    fn localize(&mut self, locale: Locale) {
+        if let Some(name) = self.names.get(locale.tag()) {
+            self.name = name.clone();
+        }
+        self.category_label = Some(self.category.label(locale).to_owned());
+    }
+}
+
+impl Localize for BreedGroup {
+    fn localize(&mut self, locale: Locale) {
+        if let Some(name) = self.names.get(locale.tag()) {
+            self.name = name.clone();
+        }
+    }
+}
+
+impl Localize for Dog {
+    fn localize(&mut self, locale: Locale) {
+        self.breed.localize(locale);
+        self.breed_components.iter_mut().for_each(|c| c.breed.localize(locale));
+        self.gender_label = Some(self.gender.label(locale).to_owned());
+    }
+}
+
+impl<T> Localize for Vec<T>
+where
+    T: Localize,
+{
+    fn localize(&mut self, locale: Locale) {
+        self.iter_mut().for_each(|v| v.localize(locale));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_str_compares_primary_language_only() {
+        assert_eq!("zh-CN".parse::<Locale>().unwrap(), Locale::Zh);
+        assert_eq!("zh_Hans".parse::<Locale>().unwrap(), Locale::Zh);
+        assert_eq!("EN-us".parse::<Locale>().unwrap(), Locale::En);
+        assert!("fr".parse::<Locale>().is_err());
+    }
+
+    #[test]
+    fn accept_language_picks_highest_quality_supported_locale() {
+        assert_eq!(Locale::from_accept_language("en-US"), Some(Locale::En));
+        assert_eq!(Locale::from_accept_language("fr-FR, zh;q=0.5, en;q=0.8"), Some(Locale::En));
+        assert_eq!(Locale::from_accept_language("en;q=0.3, zh-CN;q=0.9"), Some(Locale::Zh));
+    }
+
+    #[test]
+    fn accept_language_ignores_unsupported_and_zero_quality() {
+        assert_eq!(Locale::from_accept_language("fr, de"), None);
+        assert_eq!(Locale::from_accept_language("en;q=0, zh;q=0.1"), Some(Locale::Zh));
+        assert_eq!(Locale::from_accept_language("en;q=abc"), None);
+        assert_eq!(Locale::from_accept_language(""), None);
+    }
+}
@@ -1,4 +1,17 @@
+pub mod age;
+pub mod audit;
+pub mod batch;
+pub mod breed_import;
 pub mod entities;
 pub mod error;
+pub mod events;
+pub mod feed;
+pub mod idempotency;
+pub mod locale;
+pub mod patch;
 pub mod repository;
+pub mod search;
 pub mod service;
+pub mod sync;
+pub mod validation;
+pub mod webhook;
@@ -0,0 +1,52 @@
+use serde::{Deserialize, Deserializer};
+
+// 更新请求按JSON Merge Patch(RFC 7396)的语义区分字段的三种状态:
+// 字段缺省 => None, 不修改; 字段为null => Some(None), 清除; 字段有值 => Some(Some(v)), 修改
+pub fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// 必填字段不能清除, 为null时直接报错, 而不是当作缺省忽略掉
+pub fn non_null<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct Update {
+        #[serde(default, deserialize_with = "super::nullable")]
+        introduction: Option<Option<String>>,
+        #[serde(default, deserialize_with = "super::non_null")]
+        name: Option<String>,
+    }
+
+    fn parse(s: &str) -> Result<Update, serde_json::Error> {
+        serde_json::from_str(s)
+    }
+
+    #[test]
+    fn nullable_distinguishes_absent_null_and_value() {
+        assert_eq!(parse("{}").unwrap().introduction, None);
+        assert_eq!(parse(r#"{"introduction": null}"#).unwrap().introduction, Some(None));
+        assert_eq!(parse(r#"{"introduction": "乖"}"#).unwrap().introduction, Some(Some("乖".to_owned())));
+        assert!(parse(r#"{"introduction": 1}"#).is_err());
+    }
+
+    #[test]
+    fn non_null_rejects_null() {
+        assert_eq!(parse("{}").unwrap().name, None);
+        assert_eq!(parse(r#"{"name": "不二"}"#).unwrap().name, Some("不二".to_owned()));
+        assert!(parse(r#"{"name": null}"#).is_err());
+    }
+}
@@ -1,8 +1,14 @@
-use crate::core::entities::{Breed, Category, Dog};
+use crate::core::audit::{AuditEntry, AuditQuery};
+use crate::core::entities::{Birthday, Breed, BreedAttributes, BreedGroup, Category, Dog, DogTombstone, Gender};
 use crate::core::error::Error;
-use chrono::{DateTime, Utc};
+use crate::core::events::DomainEvent;
+use crate::core::idempotency::{IdempotencyRecord, StoredResponse};
+use crate::core::patch;
+use crate::core::webhook::{Webhook, WebhookCreate, WebhookDelivery, WebhookDeliveryQuery};
+use chrono::{DateTime, NaiveDate, Utc};
 use mongodb::bson::{doc, Document};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Pagination {
@@ -10,48 +16,163 @@ pub struct Pagination {
     pub skip: i64,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BreedGroupCreate {
+    pub name: String,
+    #[serde(default)]
+    pub names: HashMap<String, String>,
+    pub fci_number: Option<u8>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BreedCreate {
     pub category: Category,
     pub name: String,
+    #[serde(default)]
+    pub names: HashMap<String, String>,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub attributes: BreedAttributes,
+    pub group_id: Option<String>,
+    pub parent_id: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct BreedUpdate {
     pub name: Option<String>,
+    pub category: Option<Category>,
+    pub names: Option<HashMap<String, String>>,
+    pub aliases: Option<Vec<String>>,
+    pub attributes: Option<BreedAttributes>,
+    // 为null时清除, 如子品种改为独立品种
+    #[serde(default, deserialize_with = "patch::nullable")]
+    pub group_id: Option<Option<String>>,
+    #[serde(default, deserialize_with = "patch::nullable")]
+    pub parent_id: Option<Option<String>>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
 pub struct BreedQuery {
     pub id: Option<String>,
     pub category: Option<Category>,
     pub name: Option<String>,
+    pub group_id: Option<String>,
+    pub parent_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_after: Option<DateTime<Utc>>, // 包含边界
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_after: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// 狗狗中保存的品种快照, 与品种库中的品种一致
+impl From<&Breed> for BreedQuery {
+    fn from(breed: &Breed) -> Self {
+        Self {
+            id: Some(breed.id.clone()),
+            category: Some(breed.category.clone()),
+            name: Some(breed.name.clone()),
+            group_id: breed.group_id.clone(),
+            parent_id: breed.parent_id.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BreedComponentCreate {
+    pub breed: BreedQuery,
+    pub percentage: Option<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DogCreate {
     pub owner_id: String,
     pub name: String,
-    pub gender: String,
-    pub breed: BreedQuery,       // 品种
-    pub birthday: DateTime<Utc>, // 生日
-    // pub is_sterilized: bool,     // 是否绝育
-    // pub introduction: String,
+    pub gender: Gender,
+    pub breed: BreedQuery, // 品种
+    #[serde(default)]
+    pub breed_components: Vec<BreedComponentCreate>, // 混血组成
+    #[serde(default)]
+    pub unknown_mix: bool,
+    pub birthday: Birthday, // 生日
+    #[serde(default)]
+    pub is_sterilized: bool, // 是否绝育
+    #[serde(default)]
+    pub introduction: String, // 简介
     pub tags: Vec<String>,
     pub portrait_id: Option<String>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+// 狗狗的完整可写字段, 作为JSON Patch的操作对象和历史版本的快照, 品种保留完整的快照
+impl From<&Dog> for DogCreate {
+    fn from(dog: &Dog) -> Self {
+        Self {
+            owner_id: dog.owner_id.clone(),
+            name: dog.name.clone(),
+            gender: dog.gender,
+            breed: BreedQuery::from(&dog.breed),
+            breed_components: dog
+                .breed_components
+                .iter()
+                .map(|c| BreedComponentCreate {
+                    breed: BreedQuery::from(&c.breed),
+                    percentage: c.percentage,
+                })
+                .collect(),
+            unknown_mix: dog.unknown_mix,
+            birthday: dog.birthday.clone(),
+            is_sterilized: dog.is_sterilized,
+            introduction: dog.introduction.clone(),
+            tags: dog.tags.clone(),
+            portrait_id: dog.portrait_id.clone(),
+        }
+    }
+}
+
+// 狗狗资料的历史版本, 保存完整快照
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DogVersion {
+    pub dog_id: String,
+    pub version: u64,
+    pub dog: DogCreate,
+    pub actor: Option<String>, // 升级前的旧版本没有记录操作人
+    pub at: DateTime<Utc>,
+}
+
+// introduction和portrait_id可以用null清除, 其它字段为null时报错
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DogUpdate {
+    #[serde(default, deserialize_with = "patch::non_null")]
     pub name: Option<String>,
-    pub gender: Option<String>,
-    pub breed: Option<BreedQuery>,   // 品种
-    pub birthday: Option<String>,    // 生日
+    #[serde(default, deserialize_with = "patch::non_null")]
+    pub gender: Option<Gender>,
+    #[serde(default, deserialize_with = "patch::non_null")]
+    pub breed: Option<BreedQuery>, // 品种
+    #[serde(default, deserialize_with = "patch::non_null")]
+    pub breed_components: Option<Vec<BreedComponentCreate>>,
+    #[serde(default, deserialize_with = "patch::non_null")]
+    pub unknown_mix: Option<bool>,
+    #[serde(default, deserialize_with = "patch::non_null")]
+    pub birthday: Option<Birthday>, // 生日
+    #[serde(default, deserialize_with = "patch::non_null")]
     pub is_sterilized: Option<bool>, // 是否绝育
-    pub introduction: Option<String>,
+    #[serde(default, deserialize_with = "patch::nullable")]
+    pub introduction: Option<Option<String>>,
+    #[serde(default, deserialize_with = "patch::non_null")]
     pub owner_id: Option<String>,
+    #[serde(default, deserialize_with = "patch::non_null")]
     pub tags: Option<Vec<String>>,
-    pub portrait_id: Option<String>,
+    #[serde(default, deserialize_with = "patch::nullable")]
+    pub portrait_id: Option<Option<String>>,
+}
+
+// 批量修改中的一项, version与If-Match的含义相同
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DogBatchUpdate {
+    pub id: String,
+    pub version: Option<u64>,
+    pub dog: DogUpdate,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -59,16 +180,114 @@ pub struct DogQuery {
     pub id: Option<String>,
     pub id_in: Option<Vec<String>>,
     pub owner_id: Option<String>,
+    pub breed_id: Option<String>, // 匹配主品种或任一混血组成
+    pub breed_group_id: Option<String>,
+    pub born_after: Option<NaiveDate>, // 按生日可能范围的交集过滤, 包含边界
+    pub born_before: Option<NaiveDate>,
+    pub created_after: Option<DateTime<Utc>>, // 包含边界
+    pub updated_after: Option<DateTime<Utc>>,
     pub pagination: Option<Pagination>,
 }
 
 pub trait Repository {
-    async fn create_breed(&self, breed: &BreedCreate) -> Result<String, Error>;
-    async fn delete_breed(&self, id: &str) -> Result<bool, Error>;
+    async fn create_breed_group(&self, group: &BreedGroupCreate) -> Result<String, Error>;
+    async fn query_breed_groups(&self) -> Result<Vec<BreedGroup>, Error>;
+    // 以下写操作在同一个事务中记录审计日志, actor为操作人
+    async fn create_breed(&self, actor: &str, breed: &BreedCreate) -> Result<String, Error>;
+    async fn delete_breed(&self, actor: &str, id: &str) -> Result<bool, Error>;
+    // version不为None时只在版本号一致时修改, 修改成功后版本号加1
+    async fn update_breed(&self, actor: &str, id: &str, breed: &BreedUpdate, version: Option<u64>) -> Result<bool, Error>;
+    // 在同一个事务中把from品种并入into品种: 名字和别名并入into, 引用from的狗狗(包括混血组成)和子品种改为引用into, 然后删除from
+    // 返回修改的狗狗数量, 任一品种不存在时返回None
+    async fn merge_breeds(&self, actor: &str, from: &str, into: &str) -> Result<Option<u64>, Error>;
     async fn query_breeds(&self, query: &BreedQuery) -> Result<(Vec<Breed>, i64), Error>;
-    async fn create_dog(&self, dog: &DogCreate) -> Result<Dog, Error>;
-    async fn delete_dog(&self, id: &str) -> Result<bool, Error>;
-    async fn update_dog(&self, id: &str, dog: &DogUpdate) -> Result<bool, Error>;
+    async fn search_breeds(&self, keyword: &str) -> Result<Vec<Breed>, Error>;
+    async fn create_dog(&self, actor: &str, dog: &DogCreate) -> Result<Dog, Error>;
+    // 在同一个事务中创建, 按传入的顺序返回
+    async fn create_dogs(&self, actor: &str, dogs: &[DogCreate]) -> Result<Vec<Dog>, Error>;
+    // 软删除, 已删除的狗狗不出现在任何查询中
+    async fn delete_dog(&self, actor: &str, id: &str, version: Option<u64>) -> Result<bool, Error>;
+    // 恢复主人在deleted_after之后删除的狗狗
+    async fn restore_dog(&self, actor: &str, id: &str, owner_id: &str, deleted_after: DateTime<Utc>) -> Result<bool, Error>;
+    // 彻底删除在deleted_before之前删除的狗狗, 返回删除的数量
+    async fn purge_dogs(&self, deleted_before: DateTime<Utc>) -> Result<u64, Error>;
+    async fn update_dog(&self, actor: &str, id: &str, dog: &DogUpdate, version: Option<u64>) -> Result<bool, Error>;
+    // 在同一个事务中修改, 返回每一项是否有修改和修改后的版本号, 不存在或版本号不一致时为None, 只有全部成功时才提交
+    async fn update_dogs(&self, actor: &str, updates: &[DogBatchUpdate]) -> Result<Vec<Option<(bool, u64)>>, Error>;
+    // 整体替换狗狗的可写字段, 狗狗不存在或版本号不一致时返回false
+    async fn replace_dog(&self, actor: &str, id: &str, dog: &DogCreate, version: Option<u64>) -> Result<bool, Error>;
     async fn query_dogs(&self, query: &DogQuery) -> Result<Vec<Dog>, Error>;
     async fn exists_dog(&self, query: &DogQuery) -> Result<bool, Error>;
+    // 按版本号倒序
+    async fn dog_versions(&self, dog_id: &str) -> Result<Vec<DogVersion>, Error>;
+    async fn dog_version(&self, dog_id: &str, version: u64) -> Result<Option<DogVersion>, Error>;
+    // 按时间倒序
+    async fn query_audit(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, Error>;
+    // 增量同步: 按变更序号升序返回主人的序号大于since的狗狗和删除记录, 各自最多limit条
+    async fn dog_changes(&self, owner_id: &str, since: u64, limit: i64) -> Result<(Vec<Dog>, Vec<DogTombstone>), Error>;
+    // 写操作在同一个事务中把领域事件写入发件箱, 这里按写入顺序占用未投递的事件, 占用期内其他实例取不到
+    async fn claim_events(&self, now: DateTime<Utc>, lease: chrono::Duration, limit: usize) -> Result<Vec<DomainEvent>, Error>;
+    async fn mark_events_dispatched(&self, ids: &[String]) -> Result<(), Error>;
+    async fn create_webhook(&self, webhook: &WebhookCreate) -> Result<Webhook, Error>;
+    async fn webhooks(&self) -> Result<Vec<Webhook>, Error>;
+    async fn webhook(&self, id: &str) -> Result<Option<Webhook>, Error>;
+    async fn delete_webhook(&self, id: &str) -> Result<bool, Error>;
+    async fn create_webhook_deliveries(&self, deliveries: &[WebhookDelivery]) -> Result<(), Error>;
+    // 取出到期的待投递记录, 并把下次投递时间推迟lease, 避免多个实例同时投递
+    async fn claim_webhook_deliveries(&self, now: DateTime<Utc>, lease: chrono::Duration, limit: usize) -> Result<Vec<WebhookDelivery>, Error>;
+    // 保存一次投递的结果
+    async fn update_webhook_delivery(&self, delivery: &WebhookDelivery) -> Result<(), Error>;
+    // 按创建时间倒序
+    async fn query_webhook_deliveries(&self, query: &WebhookDeliveryQuery) -> Result<Vec<WebhookDelivery>, Error>;
+    // 重置为待投递并立即投递, 不存在时返回None
+    async fn redeliver_webhook(&self, delivery_id: &str) -> Result<Option<WebhookDelivery>, Error>;
+    // 记录不存在或已过期时写入并返回None, 否则返回已有的记录
+    async fn reserve_idempotency_key(&self, record: &IdempotencyRecord) -> Result<Option<IdempotencyRecord>, Error>;
+    async fn complete_idempotency_key(&self, user_id: &str, key: &str, response: &StoredResponse) -> Result<(), Error>;
+    async fn release_idempotency_key(&self, user_id: &str, key: &str) -> Result<(), Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn dog_create_from_dog_keeps_full_breed_snapshots() {
+        let breed = json!({ "id": "6534c1e563e5adcdbf8a3790", "category": "Small", "name": "柯基", "group_id": "g1", "parent_id": null });
+        let dog = serde_json::from_value::<Dog>(json!({
+            "id": "6534c1e563e5adcdbf8a3791",
+            "name": "不二",
+            "gender": "Male",
+            "breed": breed,
+            "breed_components": [{ "breed": breed, "percentage": 100 }],
+            "birthday": "2022-01-01",
+            "owner_id": "user-1",
+            "tags": [],
+            "portrait_id": null,
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": "2024-01-01T00:00:00Z",
+        }))
+        .unwrap();
+        let create = DogCreate::from(&dog);
+        for b in [&create.breed, &create.breed_components[0].breed] {
+            assert_eq!(b.id.as_deref(), Some("6534c1e563e5adcdbf8a3790"));
+            assert_eq!(b.category, Some(Category::Small));
+            assert_eq!(b.name.as_deref(), Some("柯基"));
+            assert_eq!(b.group_id.as_deref(), Some("g1"));
+        }
+        // 写回数据库后仍然能读出完整的品种
+        let stored = serde_json::from_value::<Breed>(serde_json::to_value(&create.breed).unwrap()).unwrap();
+        assert_eq!((stored.category, stored.name), (Category::Small, "柯基".to_owned()));
+    }
+
+    #[test]
+    fn breed_update_distinguishes_missing_and_null_hierarchy() {
+        let update = serde_json::from_value::<BreedUpdate>(json!({ "group_id": null, "parent_id": "6534c1e563e5adcdbf8a3790" })).unwrap();
+        assert_eq!(update.group_id, Some(None));
+        assert_eq!(update.parent_id, Some(Some("6534c1e563e5adcdbf8a3790".to_owned())));
+        let update = serde_json::from_value::<BreedUpdate>(json!({ "name": "柯基" })).unwrap();
+        assert_eq!((update.group_id, update.parent_id), (None, None));
+    }
 }
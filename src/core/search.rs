@@ -0,0 +1,161 @@
+use pinyin::ToPinyin;
+
+use crate::core::entities::Breed;
+
+// 全角转半角
+fn fold_width(c: char) -> char {
+    match c {
+        '\u{3000}' => ' ',
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        _ => c,
+    }
+}
+
+// 全角转半角, 去掉空白并转成小写, "Golden Retriever" => "goldenretriever"
+pub fn normalize(s: &str) -> String {
+    s.chars()
+        .map(fold_width)
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// 全拼和首字母, "金毛" => ("jinmao", "jm"), 非汉字原样保留
+pub fn pinyin(s: &str) -> (String, String) {
+    let s = normalize(s);
+    let mut full = String::new();
+    let mut initials = String::new();
+    for (c, p) in s.chars().zip(s.as_str().to_pinyin()) {
+        match p {
+            Some(p) => {
+                full.push_str(p.plain());
+                initials.push_str(p.first_letter());
+            }
+            None => {
+                full.push(c);
+                initials.push(c);
+            }
+        }
+    }
+    (full, initials)
+}
+
+// 存储在数据库中用于检索的关键字
+pub fn search_keys(name: &str, aliases: &[String]) -> Vec<String> {
+    let mut keys = Vec::new();
+    for n in std::iter::once(name).chain(aliases.iter().map(String::as_str)) {
+        let (full, initials) = pinyin(n);
+        for key in [normalize(n), full, initials] {
+            if !key.is_empty() && !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    keys
+}
+
+fn match_score(text: &str, keyword: &str, exact: u32, prefix: u32, contains: u32) -> Option<u32> {
+    if text == keyword {
+        Some(exact)
+    } else if text.starts_with(keyword) {
+        Some(prefix)
+    } else if text.contains(keyword) {
+        Some(contains)
+    } else {
+        None
+    }
+}
+
+// 相关度, 越大越相关, 不匹配时返回None
+pub fn score(breed: &Breed, keyword: &str) -> Option<u32> {
+    let keyword = normalize(keyword);
+    if keyword.is_empty() {
+        return None;
+    }
+    std::iter::once((breed.name.as_str(), 10))
+        .chain(breed.names.values().chain(breed.aliases.iter()).map(|a| (a.as_str(), 0)))
+        .filter_map(|(n, bonus)| {
+            let (full, initials) = pinyin(n);
+            [
+                match_score(&normalize(n), &keyword, 90, 70, 40),
+                match_score(&full, &keyword, 60, 50, 30),
+                match_score(&initials, &keyword, 55, 45, 20),
+            ]
+            .into_iter()
+            .flatten()
+            .max()
+            .map(|s| s + bonus)
+        })
+        .max()
+}
+
+// 按相关度排序, 相关度相同时名字短的在前
+pub fn rank(breeds: Vec<Breed>, keyword: &str, limit: usize) -> Vec<Breed> {
+    let mut scored = breeds.into_iter().filter_map(|b| score(&b, keyword).map(|s| (s, b))).collect::<Vec<_>>();
+    scored.sort_by(|(sa, a), (sb, b)| sb.cmp(sa).then_with(|| a.name.chars().count().cmp(&b.name.chars().count())));
+    scored.into_iter().take(limit).map(|(_, b)| b).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::entities::Category;
+
+    fn breed(name: &str, aliases: &[&str]) -> Breed {
+        Breed {
+            id: String::new(),
+            category: Category::Large,
+            name: name.to_owned(),
+            names: Default::default(),
+            aliases: aliases.iter().map(|a| a.to_string()).collect(),
+            attributes: Default::default(),
+            group_id: None,
+            parent_id: None,
+            category_label: None,
+            version: 1,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn normalize_folds_width_whitespace_and_case() {
+        assert_eq!(normalize("Golden Retriever"), "goldenretriever");
+        assert_eq!(normalize("ＧＯＬＤＥＮ　金毛"), "golden金毛");
+    }
+
+    #[test]
+    fn pinyin_keeps_non_han_characters() {
+        assert_eq!(pinyin("金毛"), ("jinmao".to_owned(), "jm".to_owned()));
+        assert_eq!(pinyin("柯基K9"), ("kejik9".to_owned(), "kjk9".to_owned()));
+    }
+
+    #[test]
+    fn search_keys_include_pinyin_of_aliases_without_duplicates() {
+        let keys = search_keys("金毛寻回犬", &["金毛".to_owned(), "Golden".to_owned()]);
+        assert_eq!(keys, ["金毛寻回犬", "jinmaoxunhuiquan", "jmxhq", "金毛", "jinmao", "jm", "golden"]);
+    }
+
+    #[test]
+    fn score_prefers_exact_over_prefix_over_contains() {
+        let b = breed("柯基", &["威尔士柯基"]);
+        let exact = score(&b, "柯基").unwrap();
+        let prefix = score(&b, "ke").unwrap();
+        let contains = score(&breed("威尔士柯基", &[]), "柯基").unwrap();
+        assert!(exact > prefix && prefix > contains);
+        assert_eq!(score(&b, "金毛"), None);
+        assert_eq!(score(&b, "  "), None);
+    }
+
+    #[test]
+    fn score_gives_name_matches_a_bonus_over_aliases() {
+        assert!(score(&breed("金毛", &[]), "金毛").unwrap() > score(&breed("金毛寻回犬", &["金毛"]), "金毛").unwrap());
+    }
+
+    #[test]
+    fn rank_sorts_by_score_then_shorter_name_and_applies_limit() {
+        let breeds = vec![breed("巨型贵宾犬", &[]), breed("贵宾", &[]), breed("玩具贵宾", &[]), breed("柯基", &[])];
+        let names = rank(breeds, "贵宾", 2).into_iter().map(|b| b.name).collect::<Vec<_>>();
+        assert_eq!(names, ["贵宾", "玩具贵宾"]);
+    }
+}
@@ -1,13 +1,32 @@
-use std::default;
+use std::{
+    collections::{HashMap, HashSet},
+    default,
+};
+
+use chrono::{Duration, Utc};
+use serde_json::json;
 
 use crate::core::{
-    error::Error,
-    repository::{BreedCreate, BreedQuery, DogCreate, DogQuery, DogUpdate, Repository},
+    error::{Error, ErrorKind},
+    repository::{
+        BreedComponentCreate, BreedCreate, BreedGroupCreate, BreedQuery, BreedUpdate, DogBatchUpdate, DogCreate, DogQuery, DogUpdate, DogVersion,
+        Repository,
+    },
 };
 
 use super::{
-    entities::{Breed, Dog},
+    age::LifeStageThresholds,
+    audit::{AuditEntry, AuditQuery},
+    batch::{BatchItem, BatchReport, BatchStatus},
+    breed_import::{ImportReport, ImportRow, ImportStatus},
+    entities::{Breed, BreedGroup, Dog},
+    events::{Dispatcher, DomainEvent},
+    idempotency::{Idempotency, IdempotencyRecord, StoredResponse, MAX_KEY_LEN},
     repository::Pagination,
+    search,
+    sync::{self, DogSync},
+    validation::Validate,
+    webhook::{self, Webhook, WebhookCreate, WebhookDelivery, WebhookDeliveryQuery},
 };
 
 pub struct Service<R>
@@ -15,6 +34,14 @@ where
     R: Repository,
 {
     repository: R,
+    life_stages: LifeStageThresholds,
+    restore_window: Duration, // 删除后多长时间内可以恢复
+    webhook_max_attempts: u32,
+    webhook_timeout: Duration,    // 单次webhook请求的超时时间, 决定投递占用的期限
+    idempotency_window: Duration, // 同一个Idempotency-Key的响应保留多长时间
+    idempotency_lease: Duration,  // 第一次请求最长的处理时间, 超过后重试可以重新占用key
+    max_batch_size: usize,
+    event_lease: Duration, // 投递一批事件最长的时间, 超过后其他实例可以重新占用
 }
 
 impl<R> Service<R>
@@ -22,38 +49,686 @@ where
     R: Repository,
 {
     pub fn new(repository: R) -> Self {
-        Self { repository }
+        Self {
+            repository,
+            life_stages: LifeStageThresholds::default(),
+            restore_window: Duration::days(30),
+            webhook_max_attempts: 8,
+            webhook_timeout: Duration::seconds(10),
+            idempotency_window: Duration::hours(24),
+            idempotency_lease: Duration::minutes(5),
+            max_batch_size: 100,
+            event_lease: Duration::minutes(1),
+        }
+    }
+
+    pub fn with_life_stages(self, life_stages: LifeStageThresholds) -> Self {
+        Self { life_stages, ..self }
+    }
+
+    pub fn with_restore_window(self, restore_window: Duration) -> Self {
+        Self { restore_window, ..self }
+    }
+
+    pub fn with_webhook_max_attempts(self, webhook_max_attempts: u32) -> Self {
+        Self {
+            webhook_max_attempts,
+            ..self
+        }
+    }
+
+    pub fn with_webhook_timeout(self, webhook_timeout: Duration) -> Self {
+        Self { webhook_timeout, ..self }
+    }
+
+    pub fn with_idempotency_window(self, idempotency_window: Duration) -> Self {
+        Self { idempotency_window, ..self }
+    }
+
+    pub fn with_idempotency_lease(self, idempotency_lease: Duration) -> Self {
+        Self { idempotency_lease, ..self }
+    }
+
+    pub fn with_max_batch_size(self, max_batch_size: usize) -> Self {
+        Self { max_batch_size, ..self }
+    }
+
+    pub fn with_event_lease(self, event_lease: Duration) -> Self {
+        Self { event_lease, ..self }
+    }
+
+    fn with_age(&self, mut dog: Dog) -> Dog {
+        dog.age = Some(self.life_stages.age(&dog, Utc::now().date_naive()));
+        dog
+    }
+
+    pub async fn audit_log(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, Error> {
+        self.repository.query_audit(query).await
+    }
+
+    pub async fn create_breed_group(&self, group: &BreedGroupCreate) -> Result<String, Error> {
+        self.repository.create_breed_group(group).await
+    }
+
+    pub async fn breed_groups(&self) -> Result<Vec<BreedGroup>, Error> {
+        self.repository.query_breed_groups().await
+    }
+
+    pub async fn group_breeds(&self, group_id: &str) -> Result<(Vec<Breed>, i64), Error> {
+        self.repository
+            .query_breeds(&BreedQuery {
+                group_id: Some(group_id.to_owned()),
+                ..Default::default()
+            })
+            .await
     }
-    pub async fn create_breed(&self, breed: BreedCreate) -> Result<String, Error> {
-        self.repository.create_breed(&breed).await
+
+    // 子品种只允许一层, 且必须和上级品种同组
+    async fn check_breed_parent(&self, group_id: Option<&String>, parent_id: &str) -> Result<(), Error> {
+        let (parents, _) = self
+            .repository
+            .query_breeds(&BreedQuery {
+                id: Some(parent_id.to_owned()),
+                ..Default::default()
+            })
+            .await?;
+        let parent = parents
+            .first()
+            .ok_or(Error::invalid_input("parent breed not exists").with_cause(parent_id.to_owned()))?;
+        if parent.parent_id.is_some() {
+            return Err(Error::invalid_input("parent breed is a sub-variety itself").with_cause(parent_id.to_owned()));
+        }
+        if group_id.is_some() && parent.group_id.as_ref() != group_id {
+            return Err(Error::invalid_input("sub-variety must belong to the same group as its parent"));
+        }
+        Ok(())
+    }
+
+    async fn has_sub_varieties(&self, id: &str) -> Result<bool, Error> {
+        let (_, total) = self
+            .repository
+            .query_breeds(&BreedQuery {
+                parent_id: Some(id.to_owned()),
+                ..Default::default()
+            })
+            .await?;
+        Ok(total > 0)
+    }
+
+    pub async fn create_breed(&self, actor: &str, breed: BreedCreate) -> Result<String, Error> {
+        breed.validate()?;
+        if let Some(parent_id) = &breed.parent_id {
+            self.check_breed_parent(breed.group_id.as_ref(), parent_id).await?;
+        }
+        self.repository.create_breed(actor, &breed).await
+    }
+
+    pub async fn delete_breed(&self, actor: &str, id: &str) -> Result<bool, Error> {
+        self.repository.delete_breed(actor, id).await
+    }
+
+    pub async fn update_breed(&self, actor: &str, id: &str, breed: &BreedUpdate, version: Option<u64>) -> Result<bool, Error> {
+        breed.validate()?;
+        // 按修改后的分组和上级品种检查层级, 没有提供的字段取当前值
+        if breed.group_id.is_some() || breed.parent_id.is_some() {
+            let current = self.breed(id).await?;
+            let group_id = breed.group_id.clone().unwrap_or(current.group_id.clone());
+            if let Some(Some(parent_id)) = &breed.parent_id {
+                if parent_id == id {
+                    return Err(Error::invalid_input("breed can not be its own parent"));
+                }
+                if self.has_sub_varieties(id).await? {
+                    return Err(Error::invalid_input("breed with sub-varieties can not be a sub-variety"));
+                }
+            }
+            if let Some(parent_id) = breed.parent_id.clone().unwrap_or(current.parent_id.clone()) {
+                self.check_breed_parent(group_id.as_ref(), &parent_id).await?;
+            } else if group_id != current.group_id && self.has_sub_varieties(id).await? {
+                return Err(Error::invalid_input("sub-variety must belong to the same group as its parent"));
+            }
+        }
+        let updated = self.repository.update_breed(actor, id, breed, version).await?;
+        if !updated && version.is_some() {
+            check_version(self.breed(id).await?.version, version)?;
+        }
+        Ok(updated)
+    }
+
+    // 与品种的唯一约束一致, 按分类和规范化后的名字去重, 已存在的品种只更新有变化的字段
+    pub async fn import_breeds(&self, actor: &str, rows: Vec<ImportRow>, dry_run: bool) -> Result<ImportReport, Error> {
+        let (existing, _) = self.repository.query_breeds(&BreedQuery::default()).await?;
+        let existing = existing
+            .into_iter()
+            .map(|b| ((b.category.to_string(), search::normalize(&b.name)), b))
+            .collect::<HashMap<_, Breed>>();
+        let mut seen = HashSet::new();
+        let mut report = ImportReport {
+            dry_run,
+            ..Default::default()
+        };
+        for ImportRow { row, name, breed } in rows {
+            let breed = match breed.and_then(|b| b.validate().map(|_| b)) {
+                Ok(breed) => breed,
+                Err(e) => {
+                    report.push(row, &name, ImportStatus::Failed, None, Some(e.to_string()));
+                    continue;
+                }
+            };
+            let key = (breed.category.to_string(), search::normalize(&breed.name));
+            if !seen.insert(key.clone()) {
+                report.push(
+                    row,
+                    &breed.name,
+                    ImportStatus::Skipped,
+                    None,
+                    Some("duplicate name in import file".into()),
+                );
+                continue;
+            }
+            let Some(current) = existing.get(&key) else {
+                let res = match &breed.parent_id {
+                    Some(parent_id) => self.check_breed_parent(breed.group_id.as_ref(), parent_id).await,
+                    None => Ok(()),
+                };
+                match res {
+                    Ok(()) if dry_run => report.push(row, &breed.name, ImportStatus::Created, None, None),
+                    Ok(()) => match self.repository.create_breed(actor, &breed).await {
+                        Ok(id) => report.push(row, &breed.name, ImportStatus::Created, Some(id), None),
+                        Err(e) => report.push(row, &breed.name, ImportStatus::Failed, None, Some(e.to_string())),
+                    },
+                    Err(e) => report.push(row, &breed.name, ImportStatus::Failed, None, Some(e.to_string())),
+                }
+                continue;
+            };
+            let update = BreedUpdate {
+                name: None,
+                category: None,
+                names: (current.names != breed.names).then_some(breed.names),
+                aliases: (current.aliases != breed.aliases).then_some(breed.aliases),
+                attributes: (current.attributes != breed.attributes).then_some(breed.attributes),
+                group_id: breed.group_id.filter(|g| current.group_id.as_ref() != Some(g)).map(Some),
+                parent_id: breed.parent_id.filter(|p| current.parent_id.as_ref() != Some(p)).map(Some),
+            };
+            let changed = update.names.is_some()
+                || update.aliases.is_some()
+                || update.attributes.is_some()
+                || update.group_id.is_some()
+                || update.parent_id.is_some();
+            if !changed {
+                report.push(
+                    row,
+                    &breed.name,
+                    ImportStatus::Skipped,
+                    Some(current.id.clone()),
+                    Some("breed already exists".into()),
+                );
+            } else if dry_run {
+                report.push(row, &breed.name, ImportStatus::Updated, Some(current.id.clone()), None);
+            } else {
+                match self.update_breed(actor, &current.id, &update, None).await {
+                    Ok(_) => report.push(row, &breed.name, ImportStatus::Updated, Some(current.id.clone()), None),
+                    Err(e) => report.push(row, &breed.name, ImportStatus::Failed, Some(current.id.clone()), Some(e.to_string())),
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    pub async fn breed(&self, id: &str) -> Result<Breed, Error> {
+        let (mut breeds, _) = self
+            .repository
+            .query_breeds(&BreedQuery {
+                id: Some(id.to_owned()),
+                ..Default::default()
+            })
+            .await?;
+        breeds.pop().ok_or(Error::not_found("breed not exists").with_cause(id.to_owned()))
     }
 
-    pub async fn delete_breed(&self, id: &str) -> Result<bool, Error> {
-        self.repository.delete_breed(id).await
+    // 把重复的品种合并到另一个品种: 名字和别名并入目标品种的别名, 狗狗改为引用目标品种, 然后删除重复品种, 在同一个事务中完成
+    pub async fn merge_breeds(&self, actor: &str, from: &str, into: &str) -> Result<u64, Error> {
+        if from == into {
+            return Err(Error::invalid_input("can not merge a breed into itself"));
+        }
+        // 合并后子品种改为引用目标品种, 仍然只允许一层
+        let target = self.breed(into).await?;
+        if target.parent_id.as_deref() == Some(from) {
+            return Err(Error::invalid_input("can not merge a breed into its own sub-variety"));
+        }
+        if target.parent_id.is_some() && self.has_sub_varieties(from).await? {
+            return Err(Error::invalid_input("can not merge a breed with sub-varieties into a sub-variety"));
+        }
+        self.repository
+            .merge_breeds(actor, from, into)
+            .await?
+            .ok_or(Error::not_found("breed not exists").with_cause(from.to_owned()))
     }
 
     pub async fn query_breeds(&self, query: &BreedQuery) -> Result<(Vec<Breed>, i64), Error> {
         self.repository.query_breeds(query).await
     }
 
-    pub async fn create_dog(&self, dog: &DogCreate) -> Result<Dog, Error> {
-        self.repository.create_dog(dog).await
+    pub async fn search_breeds(&self, keyword: &str, limit: usize) -> Result<Vec<Breed>, Error> {
+        let normalized = search::normalize(keyword);
+        if normalized.is_empty() {
+            return Ok(vec![]);
+        }
+        let breeds = self.repository.search_breeds(&normalized).await?;
+        Ok(search::rank(breeds, keyword, limit))
+    }
+
+    // 品种只按id引用, 保存品种库中的快照, 客户端提供的分类和名字被忽略, 品种不存在时返回422
+    async fn breed_snapshot(&self, breed: &BreedQuery) -> Result<BreedQuery, Error> {
+        let id = breed.id.as_deref().ok_or(Error::invalid_input("breed id must not be empty"))?;
+        match self.breed(id).await {
+            Ok(b) => Ok(BreedQuery::from(&b)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::invalid_input("breed not exists").with_cause(id.to_owned())),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn resolve_components(&self, components: &mut [BreedComponentCreate]) -> Result<(), Error> {
+        for c in components {
+            c.breed = self.breed_snapshot(&c.breed).await?;
+        }
+        Ok(())
+    }
+
+    async fn resolve_dog(&self, dog: &DogCreate) -> Result<DogCreate, Error> {
+        let mut dog = dog.clone();
+        dog.breed = self.breed_snapshot(&dog.breed).await?;
+        self.resolve_components(&mut dog.breed_components).await?;
+        Ok(dog)
+    }
+
+    async fn resolve_dog_update(&self, dog: &DogUpdate) -> Result<DogUpdate, Error> {
+        let mut dog = dog.clone();
+        if let Some(breed) = &dog.breed {
+            dog.breed = Some(self.breed_snapshot(breed).await?);
+        }
+        if let Some(components) = &mut dog.breed_components {
+            self.resolve_components(components).await?;
+        }
+        Ok(dog)
+    }
+
+    pub async fn create_dog(&self, actor: &str, dog: &DogCreate) -> Result<Dog, Error> {
+        dog.validate()?;
+        let dog = &self.resolve_dog(dog).await?;
+        self.repository.create_dog(actor, dog).await.map(|d| self.with_age(d))
+    }
+
+    pub async fn update_dog_portrait(&self, actor: &str, id: &str, portrait_id: &str, version: Option<u64>) -> Result<bool, Error> {
+        let update = DogUpdate {
+            portrait_id: Some(Some(portrait_id.to_owned())),
+            ..default::Default::default()
+        };
+        self.update_dog(actor, id, &update, version).await
     }
 
-    pub async fn update_dog_portrait(&self, id: &str, portrait_id: &str) -> Result<bool, Error> {
+    // 混血组成的占比和"血统不明"要一起校验, 只修改其中一项时另一项取当前保存的值
+    async fn validate_dog_update(&self, id: &str, dog: &DogUpdate) -> Result<(), Error> {
+        if dog.breed_components.is_some() == dog.unknown_mix.is_some() {
+            return dog.validate();
+        }
+        let current = self.dog(id).await?;
+        let mut dog = dog.clone();
+        dog.unknown_mix.get_or_insert(current.unknown_mix);
+        dog.breed_components.get_or_insert_with(|| DogCreate::from(&current).breed_components);
+        dog.validate()
+    }
+
+    pub async fn update_dog(&self, actor: &str, id: &str, dog: &DogUpdate, version: Option<u64>) -> Result<bool, Error> {
+        self.validate_dog_update(id, dog).await?;
+        let dog = &self.resolve_dog_update(dog).await?;
+        let updated = self.repository.update_dog(actor, id, dog, version).await?;
+        if !updated {
+            check_version(self.dog(id).await?.version, version)?;
+        }
+        Ok(updated)
+    }
+
+    // 只有当前主人可以转让, owner_id为当前主人, 不是当前主人时视为狗狗不存在
+    pub async fn transfer_dog(&self, actor: &str, id: &str, owner_id: &str, to: &str, version: Option<u64>) -> Result<Dog, Error> {
+        if self.dog(id).await?.owner_id != owner_id {
+            return Err(Error::not_found("dog not found").with_cause(id.to_owned()));
+        }
+        let update = DogUpdate {
+            owner_id: Some(to.to_owned()),
+            ..Default::default()
+        };
+        if !self.update_dog(actor, id, &update, version).await? {
+            return Err(Error::not_found("dog not found").with_cause(id.to_owned()));
+        }
+        self.dog(id).await
+    }
+
+    fn check_batch_size(&self, size: usize) -> Result<(), Error> {
+        if size > self.max_batch_size {
+            return Err(Error::invalid_input("too many items in batch").with_details(json!({ "max_batch_size": self.max_batch_size })));
+        }
+        Ok(())
+    }
+
+    // atomic为false时逐个创建, 互不影响; 为true时先全部校验, 有任一项失败则都不创建
+    pub async fn create_dogs(&self, actor: &str, items: Vec<BatchItem<DogCreate>>, atomic: bool) -> Result<BatchReport, Error> {
+        self.check_batch_size(items.len())?;
+        let mut report = BatchReport::new(atomic);
+        if !atomic {
+            for BatchItem { index, item } in items {
+                let res = match item {
+                    Ok(dog) => self.create_dog(actor, &dog).await,
+                    Err(e) => Err(e),
+                };
+                match res {
+                    Ok(dog) => report.push(index, BatchStatus::Created, Some(dog.id), Some(dog.version)),
+                    Err(e) => report.fail(index, None, &e),
+                }
+            }
+            return Ok(report);
+        }
+        let mut checked = Vec::with_capacity(items.len());
+        for BatchItem { index, item } in items {
+            let item = match item.and_then(|d| d.validate().map(|_| d)) {
+                Ok(dog) => self.resolve_dog(&dog).await,
+                Err(e) => Err(e),
+            };
+            checked.push((index, item));
+        }
+        let items = checked;
+        if items.iter().any(|(_, item)| item.is_err()) {
+            for (index, item) in items {
+                match item {
+                    Ok(_) => report.push(index, BatchStatus::Skipped, None, None),
+                    Err(e) => report.fail(index, None, &e),
+                }
+            }
+            return Ok(report);
+        }
+        let (indexes, dogs): (Vec<usize>, Vec<DogCreate>) = items.into_iter().map(|(index, item)| (index, item.unwrap())).unzip();
+        let created = self.repository.create_dogs(actor, &dogs).await?;
+        for (index, dog) in indexes.into_iter().zip(created) {
+            let dog = self.with_age(dog);
+            report.push(index, BatchStatus::Created, Some(dog.id), Some(dog.version));
+        }
+        Ok(report)
+    }
+
+    // 与create_dogs相同, atomic为true时任一项狗狗不存在或版本号不一致都会回滚所有修改
+    pub async fn update_dogs(&self, actor: &str, items: Vec<BatchItem<DogBatchUpdate>>, atomic: bool) -> Result<BatchReport, Error> {
+        self.check_batch_size(items.len())?;
+        let mut report = BatchReport::new(atomic);
+        if !atomic {
+            for BatchItem { index, item } in items {
+                let item = match item {
+                    Ok(item) => item,
+                    Err(e) => {
+                        report.fail(index, None, &e);
+                        continue;
+                    }
+                };
+                let res = match self.update_dog(actor, &item.id, &item.dog, item.version).await {
+                    Ok(updated) => self.dog(&item.id).await.map(|d| (updated, d.version)),
+                    Err(e) => Err(e),
+                };
+                match res {
+                    Ok((true, version)) => report.push(index, BatchStatus::Updated, Some(item.id), Some(version)),
+                    Ok((false, version)) => report.push(index, BatchStatus::Unchanged, Some(item.id), Some(version)),
+                    Err(e) => report.fail(index, Some(item.id), &e),
+                }
+            }
+            return Ok(report);
+        }
+        // 先全部校验, 是否有变化由仓储在事务中判断
+        let mut checked = Vec::with_capacity(items.len());
+        for BatchItem { index, item } in items {
+            let res = match item {
+                Ok(mut item) => {
+                    let dog = match self.validate_dog_update(&item.id, &item.dog).await {
+                        Ok(()) => self.resolve_dog_update(&item.dog).await,
+                        Err(e) => Err(e),
+                    };
+                    match dog {
+                        Ok(dog) => {
+                            item.dog = dog;
+                            Ok(item)
+                        }
+                        Err(e) => Err((Some(item.id), e)),
+                    }
+                }
+                Err(e) => Err((None, e)),
+            };
+            checked.push((index, res));
+        }
+        if checked.iter().any(|(_, res)| res.is_err()) {
+            for (index, res) in checked {
+                match res {
+                    Ok(item) => report.push(index, BatchStatus::Skipped, Some(item.id), None),
+                    Err((id, e)) => report.fail(index, id, &e),
+                }
+            }
+            return Ok(report);
+        }
+        let mut indexes = Vec::with_capacity(checked.len());
+        let mut updates = Vec::with_capacity(checked.len());
+        for (index, item) in checked.into_iter().filter_map(|(index, res)| res.ok().map(|r| (index, r))) {
+            indexes.push(index);
+            updates.push(item);
+        }
+        let updated = self.repository.update_dogs(actor, &updates).await?;
+        if updated.iter().any(Option::is_none) {
+            for ((index, item), updated) in indexes.into_iter().zip(updates).zip(updated) {
+                if updated.is_some() {
+                    report.push(index, BatchStatus::Skipped, Some(item.id), None);
+                    continue;
+                }
+                let e = match self.dog(&item.id).await {
+                    Ok(current) => check_version(current.version, item.version)
+                        .err()
+                        .unwrap_or(Error::not_found("dog not exists")),
+                    Err(e) => e,
+                };
+                report.fail(index, Some(item.id), &e);
+            }
+            return Ok(report);
+        }
+        // 此时每一项都修改成功
+        for ((index, item), (changed, version)) in indexes.into_iter().zip(updates).zip(updated.into_iter().flatten()) {
+            let status = if changed { BatchStatus::Updated } else { BatchStatus::Unchanged };
+            report.push(index, status, Some(item.id), Some(version));
+        }
+        Ok(report)
+    }
+
+    pub async fn delete_dog(&self, actor: &str, id: &str, version: Option<u64>) -> Result<(), Error> {
+        if !self.repository.delete_dog(actor, id, version).await? {
+            check_version(self.dog(id).await?.version, version)?;
+            return Err(Error::not_found("dog not exists").with_cause(id.to_owned()));
+        }
+        Ok(())
+    }
+
+    // 只有主人可以恢复自己删除的狗狗
+    pub async fn restore_dog(&self, actor: &str, id: &str, owner_id: &str) -> Result<Dog, Error> {
+        if !self.repository.restore_dog(actor, id, owner_id, Utc::now() - self.restore_window).await? {
+            return Err(Error::not_found("no restorable dog").with_cause(id.to_owned()));
+        }
+        self.dog(id).await
+    }
+
+    // 彻底删除超过保留期的狗狗, 保留期不短于可恢复的期限
+    pub async fn purge_dogs(&self, retention: Duration) -> Result<u64, Error> {
+        self.repository.purge_dogs(Utc::now() - retention.max(self.restore_window)).await
+    }
+
+    // 投递发件箱中未投递的事件, 返回本次投递的数量, 投递失败的事件在占用过期后重新投递
+    pub async fn dispatch_events(&self, dispatcher: &Dispatcher, limit: usize) -> Result<usize, Error> {
+        let events = self.repository.claim_events(Utc::now(), self.event_lease, limit).await?;
+        if events.is_empty() {
+            return Ok(0);
+        }
+        dispatcher.publish(&events).await?;
         self.repository
-            .update_dog(
-                id,
-                &DogUpdate {
-                    portrait_id: Some(portrait_id.to_owned()),
-                    ..default::Default::default()
+            .mark_events_dispatched(&events.iter().map(|e| e.id.clone()).collect::<Vec<_>>())
+            .await?;
+        Ok(events.len())
+    }
+
+    pub async fn create_webhook(&self, webhook: &WebhookCreate) -> Result<Webhook, Error> {
+        webhook.validate()?;
+        self.repository.create_webhook(webhook).await
+    }
+
+    pub async fn webhooks(&self) -> Result<Vec<Webhook>, Error> {
+        self.repository.webhooks().await
+    }
+
+    pub async fn webhook(&self, id: &str) -> Result<Webhook, Error> {
+        self.repository.webhook(id).await?.ok_or(Error::not_found("webhook not found"))
+    }
+
+    // 已经排队的投递在投递时发现订阅不存在, 直接进入死信
+    pub async fn delete_webhook(&self, id: &str) -> Result<(), Error> {
+        if !self.repository.delete_webhook(id).await? {
+            return Err(Error::not_found("webhook not found"));
+        }
+        Ok(())
+    }
+
+    pub async fn enqueue_webhooks(&self, events: &[DomainEvent]) -> Result<(), Error> {
+        let webhooks = self.repository.webhooks().await?;
+        let deliveries = events
+            .iter()
+            .flat_map(|event| webhooks.iter().filter(|w| w.matches(event)).map(|w| WebhookDelivery::new(&w.id, event)))
+            .collect::<Vec<_>>();
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+        self.repository.create_webhook_deliveries(&deliveries).await
+    }
+
+    // 投递到期的webhook, 返回本次处理的数量
+    // 逐个占用并发送, 占用期限只需覆盖一次请求, 不会因为同一批中前面的请求慢而过期被其他实例重复投递
+    pub async fn deliver_webhooks(&self, client: &reqwest::Client, limit: usize) -> Result<usize, Error> {
+        // 留出查询订阅和更新投递状态的时间
+        let lease = self.webhook_timeout * 2;
+        let mut webhooks = HashMap::new();
+        let mut count = 0;
+        while count < limit {
+            let Some(mut delivery) = self.repository.claim_webhook_deliveries(Utc::now(), lease, 1).await?.pop() else {
+                break;
+            };
+            count += 1;
+            if !webhooks.contains_key(&delivery.webhook_id) {
+                webhooks.insert(delivery.webhook_id.clone(), self.repository.webhook(&delivery.webhook_id).await?);
+            }
+            match &webhooks[&delivery.webhook_id] {
+                Some(webhook) => match webhook::send(client, webhook, &delivery).await {
+                    Ok(()) => delivery.succeeded(),
+                    Err(e) => delivery.failed(e, self.webhook_max_attempts),
                 },
-            )
-            .await
+                None => delivery.failed("webhook deleted".to_owned(), 0),
+            }
+            self.repository.update_webhook_delivery(&delivery).await?;
+        }
+        Ok(count)
     }
 
-    pub async fn update_dog(&self, id: &str, dog: &DogUpdate) -> Result<bool, Error> {
-        self.repository.update_dog(id, dog).await
+    pub async fn webhook_deliveries(&self, query: &WebhookDeliveryQuery) -> Result<Vec<WebhookDelivery>, Error> {
+        self.repository.query_webhook_deliveries(query).await
+    }
+
+    pub async fn redeliver_webhook(&self, delivery_id: &str) -> Result<WebhookDelivery, Error> {
+        self.repository
+            .redeliver_webhook(delivery_id)
+            .await?
+            .ok_or(Error::not_found("webhook delivery not found"))
+    }
+
+    // 占用幂等key, 已有相同请求的响应时返回Replay, 请求不同或第一次请求还在处理中时返回409
+    pub async fn begin_idempotent(&self, user_id: &str, key: &str, fingerprint: String) -> Result<Idempotency, Error> {
+        if key.is_empty() || key.len() > MAX_KEY_LEN || !key.is_ascii() {
+            return Err(Error::invalid_input("invalid idempotency key"));
+        }
+        let record = IdempotencyRecord::new(user_id, key, fingerprint, self.idempotency_lease, self.idempotency_window);
+        let Some(existing) = self.repository.reserve_idempotency_key(&record).await? else {
+            return Ok(Idempotency::New);
+        };
+        if existing.fingerprint != record.fingerprint {
+            return Err(Error::conflict("idempotency key was used for a different request"));
+        }
+        match existing.response {
+            Some(response) => Ok(Idempotency::Replay(response)),
+            None => Err(Error::conflict("a request with the same idempotency key is in progress")),
+        }
+    }
+
+    pub async fn complete_idempotent(&self, user_id: &str, key: &str, response: &StoredResponse) -> Result<(), Error> {
+        self.repository.complete_idempotency_key(user_id, key, response).await
+    }
+
+    // 请求失败时释放key, 允许客户端重试
+    pub async fn release_idempotent(&self, user_id: &str, key: &str) -> Result<(), Error> {
+        self.repository.release_idempotency_key(user_id, key).await
+    }
+
+    pub async fn dog(&self, id: &str) -> Result<Dog, Error> {
+        let mut dogs = self
+            .repository
+            .query_dogs(&DogQuery {
+                id: Some(id.to_owned()),
+                ..Default::default()
+            })
+            .await?;
+        dogs.pop()
+            .map(|d| self.with_age(d))
+            .ok_or(Error::not_found("dog not exists").with_cause(id.to_owned()))
+    }
+
+    // JSON Patch和恢复旧版本也经过这里, 品种按id重新取品种库中的快照, 不信任补丁或旧版本中的品种内容
+    pub async fn replace_dog(&self, actor: &str, id: &str, dog: &DogCreate, version: Option<u64>) -> Result<Dog, Error> {
+        dog.validate()?;
+        let dog = &self.resolve_dog(dog).await?;
+        if !self.repository.replace_dog(actor, id, dog, version).await? {
+            check_version(self.dog(id).await?.version, version)?;
+            return Err(Error::not_found("dog not exists").with_cause(id.to_owned()));
+        }
+        self.dog(id).await
+    }
+
+    // 在狗狗的完整可写字段上应用JSON Patch(RFC 6902), 任一操作失败则整个补丁都不生效
+    // 写回时校验读取时的版本号, 期间被其他人修改过则返回412
+    pub async fn patch_dog(&self, actor: &str, id: &str, patch: &json_patch::Patch, version: Option<u64>) -> Result<Dog, Error> {
+        let current = self.dog(id).await?;
+        check_version(current.version, version)?;
+        let mut doc = serde_json::to_value(DogCreate::from(&current)).map_err(|e| Error::new("failed to patch dog").with_cause(e))?;
+        json_patch::patch(&mut doc, &patch.0).map_err(|e| match e.kind {
+            json_patch::PatchErrorKind::TestFailed => Error::conflict("json patch test failed").with_cause(e),
+            _ => Error::invalid_input("invalid json patch").with_cause(e),
+        })?;
+        let dog = serde_json::from_value::<DogCreate>(doc).map_err(|e| Error::invalid_input("invalid patched dog").with_cause(e))?;
+        self.replace_dog(actor, id, &dog, Some(current.version)).await
+    }
+
+    pub async fn dog_versions(&self, id: &str) -> Result<Vec<DogVersion>, Error> {
+        self.dog(id).await?;
+        self.repository.dog_versions(id).await
+    }
+
+    pub async fn dog_version(&self, id: &str, version: u64) -> Result<DogVersion, Error> {
+        self.dog(id).await?;
+        self.repository
+            .dog_version(id, version)
+            .await?
+            .ok_or(Error::not_found("dog version not exists").with_cause(format!("{}@{}", id, version)))
+    }
+
+    // 以旧版本的内容创建一个新版本, 主人保持不变
+    pub async fn revert_dog(&self, actor: &str, id: &str, to_version: u64, version: Option<u64>) -> Result<Dog, Error> {
+        let current = self.dog(id).await?;
+        let mut dog = self.dog_version(id, to_version).await?.dog;
+        dog.owner_id = current.owner_id;
+        self.replace_dog(actor, id, &dog, version).await
     }
 
     pub async fn my_dogs(&self, owner_id: &str, pagination: Option<Pagination>) -> Result<Vec<Dog>, Error> {
@@ -64,10 +739,24 @@ where
                 ..default::Default::default()
             })
             .await
+            .map(|dogs| dogs.into_iter().map(|d| self.with_age(d)).collect())
     }
 
     pub async fn query_dogs(&self, query: &DogQuery) -> Result<Vec<Dog>, Error> {
-        self.repository.query_dogs(query).await
+        self.repository
+            .query_dogs(query)
+            .await
+            .map(|dogs| dogs.into_iter().map(|d| self.with_age(d)).collect())
+    }
+
+    // 未提供令牌时从头同步, 即返回主人的全部狗狗
+    pub async fn sync_dogs(&self, owner_id: &str, token: Option<&str>, limit: usize) -> Result<DogSync, Error> {
+        let since = token.map(sync::parse_token).transpose()?.unwrap_or_default();
+        // 多取一条用于判断是否还有更多变更
+        let (dogs, deleted) = self.repository.dog_changes(owner_id, since, limit as i64 + 1).await?;
+        let mut sync = DogSync::new(dogs, deleted, since, limit);
+        sync.dogs = sync.dogs.into_iter().map(|d| self.with_age(d)).collect();
+        Ok(sync)
     }
 
     pub async fn is_owner_of_the_dog(&self, owner_id: &str, dog_id: &str) -> Result<bool, Error> {
@@ -80,3 +769,13 @@ where
             .await
     }
 }
+
+// If-Match中的版本号与当前版本号不一致时返回412, 并告知当前版本号
+fn check_version(current: u64, expected: Option<u64>) -> Result<(), Error> {
+    match expected {
+        Some(expected) if expected != current => {
+            Err(Error::precondition_failed("version mismatch").with_details(json!({ "current_version": current })))
+        }
+        _ => Ok(()),
+    }
+}
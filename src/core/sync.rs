@@ -0,0 +1,107 @@
+use serde::Serialize;
+
+use crate::core::{
+    entities::{Dog, DogTombstone},
+    error::Error,
+};
+
+// 同步令牌为上次同步到的变更序号, 对客户端不透明
+pub fn parse_token(token: &str) -> Result<u64, Error> {
+    token
+        .parse()
+        .map_err(|_| Error::invalid_input("invalid sync token").with_cause(token.to_owned()))
+}
+
+// 客户端应先删除deleted中的狗狗, 再写入dogs中的狗狗
+#[derive(Debug, Serialize)]
+pub struct DogSync {
+    pub dogs: Vec<Dog>,
+    pub deleted: Vec<DogTombstone>,
+    pub token: String,
+    pub has_more: bool, // 为true时应使用新令牌继续同步
+}
+
+impl DogSync {
+    // dogs和tombstones各自按序号升序, 合并后只保留序号最小的limit条, 保证下次同步不会漏掉变更
+    pub fn new(mut dogs: Vec<Dog>, mut deleted: Vec<DogTombstone>, since: u64, limit: usize) -> Self {
+        let mut seqs = dogs.iter().map(|d| d.seq).chain(deleted.iter().map(|t| t.seq)).collect::<Vec<_>>();
+        seqs.sort_unstable();
+        let has_more = seqs.len() > limit;
+        seqs.truncate(limit);
+        let last = seqs.last().copied().unwrap_or(since);
+        dogs.retain(|d| d.seq <= last);
+        deleted.retain(|t| t.seq <= last);
+        Self {
+            dogs,
+            deleted,
+            token: last.to_string(),
+            has_more,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::*;
+
+    fn dog(seq: u64) -> Dog {
+        let breed = json!({ "id": "6534c1e563e5adcdbf8a3790", "category": "Small", "name": "柯基" });
+        serde_json::from_value(json!({
+            "id": format!("dog-{seq}"),
+            "name": "不二",
+            "gender": "Male",
+            "breed": breed,
+            "breed_components": [{ "breed": breed, "percentage": 100 }],
+            "birthday": "2022-01-01",
+            "owner_id": "user-1",
+            "tags": [],
+            "portrait_id": null,
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": "2024-01-01T00:00:00Z",
+            "seq": seq,
+        }))
+        .unwrap()
+    }
+
+    fn tombstone(seq: u64) -> DogTombstone {
+        DogTombstone {
+            id: format!("dog-{seq}"),
+            deleted_at: Utc::now(),
+            seq,
+        }
+    }
+
+    #[test]
+    fn parse_token_rejects_garbage() {
+        assert_eq!(parse_token("42").unwrap(), 42);
+        assert!(parse_token("abc").is_err());
+        assert!(parse_token("-1").is_err());
+    }
+
+    #[test]
+    fn new_keeps_lowest_seqs_across_dogs_and_tombstones() {
+        let sync = DogSync::new(vec![dog(3), dog(5), dog(6)], vec![tombstone(4), tombstone(7)], 2, 3);
+        assert_eq!(sync.dogs.iter().map(|d| d.seq).collect::<Vec<_>>(), vec![3, 5]);
+        assert_eq!(sync.deleted.iter().map(|t| t.seq).collect::<Vec<_>>(), vec![4]);
+        assert_eq!(sync.token, "5");
+        assert!(sync.has_more);
+    }
+
+    #[test]
+    fn new_returns_everything_when_within_limit() {
+        let sync = DogSync::new(vec![dog(3)], vec![tombstone(4)], 2, 2);
+        assert_eq!((sync.dogs.len(), sync.deleted.len()), (1, 1));
+        assert_eq!(sync.token, "4");
+        assert!(!sync.has_more);
+    }
+
+    #[test]
+    fn new_keeps_token_when_nothing_changed() {
+        let sync = DogSync::new(vec![], vec![], 9, 10);
+        assert_eq!(sync.token, "9");
+        assert!(!sync.has_more);
+    }
+}
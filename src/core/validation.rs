@@ -0,0 +1,533 @@
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::core::{
+    entities::{Birthday, BreedAttributes, Range},
+    error::Error,
+    repository::{BreedComponentCreate, BreedCreate, BreedQuery, BreedUpdate, DogCreate, DogUpdate},
+    webhook::WebhookCreate,
+};
+
+pub const MAX_NAME_LEN: usize = 32;
+pub const MAX_INTRODUCTION_LEN: usize = 2000;
+pub const MAX_TAGS: usize = 20;
+pub const MAX_TAG_LEN: usize = 16;
+pub const MAX_ALIASES: usize = 20;
+pub const MAX_ID_LEN: usize = 64;
+pub const MAX_AGE_YEARS: i64 = 40;
+pub const MAX_URL_LEN: usize = 2048;
+pub const MIN_SECRET_LEN: usize = 16;
+pub const MAX_SECRET_LEN: usize = 256;
+
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+// 收集所有字段的错误, 一次性返回给客户端
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn error(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    pub fn check(&mut self, ok: bool, field: impl Into<String>, message: impl Into<String>) {
+        if !ok {
+            self.error(field, message);
+        }
+    }
+
+    // 单行文本, 如名字, 按字符数计算长度, 空白字符串视为空
+    pub fn text(&mut self, field: &str, value: &str, min: usize, max: usize) {
+        self.length(field, value, min, max);
+        if value.chars().any(char::is_control) {
+            self.error(field, "must not contain control characters");
+        }
+    }
+
+    // 多行文本, 如简介, 允许换行和制表符
+    pub fn multiline_text(&mut self, field: &str, value: &str, min: usize, max: usize) {
+        self.length(field, value, min, max);
+        if value.chars().any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t')) {
+            self.error(field, "must not contain control characters other than line breaks and tabs");
+        }
+    }
+
+    fn length(&mut self, field: &str, value: &str, min: usize, max: usize) {
+        let len = value.trim().chars().count();
+        if len < min {
+            self.error(
+                field,
+                if min == 1 {
+                    "must not be empty".to_owned()
+                } else {
+                    format!("must be at least {} characters", min)
+                },
+            );
+        } else if value.chars().count() > max {
+            self.error(field, format!("must be at most {} characters", max));
+        }
+    }
+
+    // 外部系统的id, 如用户id, 头像id
+    pub fn id(&mut self, field: &str, value: &str) {
+        let ok = !value.is_empty() && value.len() <= MAX_ID_LEN && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        self.check(ok, field, "invalid id");
+    }
+
+    // 本服务生成的id(MongoDB ObjectId)
+    pub fn object_id(&mut self, field: &str, value: &str) {
+        self.check(value.len() == 24 && value.chars().all(|c| c.is_ascii_hexdigit()), field, "invalid id");
+    }
+
+    pub fn birthday(&mut self, field: &str, value: &Birthday) {
+        let today = Utc::now().date_naive();
+        if value.earliest() > today {
+            self.error(field, "must not be in the future");
+        } else if (today - value.latest()).num_days() > MAX_AGE_YEARS * 366 {
+            self.error(field, format!("must be within the last {} years", MAX_AGE_YEARS));
+        }
+    }
+
+    pub fn tags(&mut self, field: &str, tags: &[String]) {
+        self.check(tags.len() <= MAX_TAGS, field, format!("must have at most {} tags", MAX_TAGS));
+        for (i, tag) in tags.iter().enumerate() {
+            let f = format!("{}[{}]", field, i);
+            self.text(&f, tag, 1, MAX_TAG_LEN);
+            self.check(
+                !tag.chars().any(|c| c.is_whitespace() || c == ','),
+                &f,
+                "must not contain whitespace or commas",
+            );
+            if tags[..i].contains(tag) {
+                self.error(f, "duplicate tag");
+            }
+        }
+    }
+
+    fn breed(&mut self, field: &str, breed: &BreedQuery) {
+        match &breed.id {
+            Some(id) => self.object_id(&format!("{}.id", field), id),
+            None => self.error(format!("{}.id", field), "must not be empty"),
+        }
+    }
+
+    // 混血组成: 单项占比1~100, 总和不超过100, 全部已知且非"血统不明"时总和应为100(允许1%的舍入误差)
+    pub fn breed_components(&mut self, field: &str, components: &[BreedComponentCreate], unknown_mix: bool) {
+        for (i, c) in components.iter().enumerate() {
+            self.breed(&format!("{}[{}].breed", field, i), &c.breed);
+            if c.breed.id.is_some() && components[..i].iter().any(|o| o.breed.id == c.breed.id) {
+                self.error(format!("{}[{}].breed", field, i), "duplicate breed");
+            }
+            if matches!(c.percentage, Some(p) if p == 0 || p > 100) {
+                self.error(format!("{}[{}].percentage", field, i), "must be between 1 and 100");
+            }
+        }
+        let sum = components.iter().filter_map(|c| c.percentage).map(u32::from).sum::<u32>();
+        if sum > 100 {
+            self.error(field, "percentages sum to more than 100");
+        } else if !components.is_empty() && !unknown_mix && components.iter().all(|c| c.percentage.is_some()) && sum < 99 {
+            self.error(field, "percentages must sum to 100 unless the dog is an unknown mix");
+        }
+    }
+
+    fn range<T>(&mut self, field: &str, range: &Option<Range<T>>)
+    where
+        T: PartialOrd + Default,
+    {
+        if let Some(r) = range {
+            self.check(r.min > T::default(), field, "must be positive");
+            self.check(r.min <= r.max, field, "min must not be greater than max");
+        }
+    }
+
+    fn attributes(&mut self, field: &str, attributes: &BreedAttributes) {
+        self.range(&format!("{}.weight_kg", field), &attributes.weight_kg);
+        self.range(&format!("{}.height_cm", field), &attributes.height_cm);
+        self.range(&format!("{}.daily_exercise_minutes", field), &attributes.daily_exercise_minutes);
+        self.range(&format!("{}.lifespan_years", field), &attributes.lifespan_years);
+    }
+
+    fn aliases(&mut self, field: &str, aliases: &[String]) {
+        self.check(aliases.len() <= MAX_ALIASES, field, format!("must have at most {} aliases", MAX_ALIASES));
+        for (i, alias) in aliases.iter().enumerate() {
+            self.text(&format!("{}[{}]", field, i), alias, 1, MAX_NAME_LEN);
+        }
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        let summary = self
+            .errors
+            .iter()
+            .map(|e| format!("{} {}", e.field, e.message))
+            .collect::<Vec<_>>()
+            .join("; ");
+        Err(Error::invalid_input("validation failed")
+            .with_cause(summary)
+            .with_details(json!({ "fields": self.errors })))
+    }
+}
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), Error>;
+}
+
+impl Validate for DogCreate {
+    fn validate(&self) -> Result<(), Error> {
+        let mut v = Validator::default();
+        v.id("owner_id", &self.owner_id);
+        v.text("name", &self.name, 1, MAX_NAME_LEN);
+        v.breed("breed", &self.breed);
+        v.breed_components("breed_components", &self.breed_components, self.unknown_mix);
+        v.birthday("birthday", &self.birthday);
+        v.multiline_text("introduction", &self.introduction, 0, MAX_INTRODUCTION_LEN);
+        v.tags("tags", &self.tags);
+        if let Some(portrait_id) = &self.portrait_id {
+            v.id("portrait_id", portrait_id);
+        }
+        v.finish()
+    }
+}
+
+impl Validate for DogUpdate {
+    fn validate(&self) -> Result<(), Error> {
+        let mut v = Validator::default();
+        if let Some(owner_id) = &self.owner_id {
+            v.id("owner_id", owner_id);
+        }
+        if let Some(name) = &self.name {
+            v.text("name", name, 1, MAX_NAME_LEN);
+        }
+        if let Some(breed) = &self.breed {
+            v.breed("breed", breed);
+        }
+        // 只修改其中一项时, 调用方应先用当前保存的值补全另一项(见Service::validate_dog_update), 这里不假定为false
+        if let Some(components) = &self.breed_components {
+            v.breed_components("breed_components", components, self.unknown_mix.unwrap_or(true));
+        }
+        if let Some(birthday) = &self.birthday {
+            v.birthday("birthday", birthday);
+        }
+        if let Some(Some(introduction)) = &self.introduction {
+            v.multiline_text("introduction", introduction, 0, MAX_INTRODUCTION_LEN);
+        }
+        if let Some(tags) = &self.tags {
+            v.tags("tags", tags);
+        }
+        if let Some(Some(portrait_id)) = &self.portrait_id {
+            v.id("portrait_id", portrait_id);
+        }
+        v.finish()
+    }
+}
+
+impl Validate for BreedCreate {
+    fn validate(&self) -> Result<(), Error> {
+        let mut v = Validator::default();
+        v.text("name", &self.name, 1, MAX_NAME_LEN);
+        for (locale, name) in &self.names {
+            v.text(&format!("names.{}", locale), name, 1, MAX_NAME_LEN);
+        }
+        v.aliases("aliases", &self.aliases);
+        v.attributes("attributes", &self.attributes);
+        if let Some(group_id) = &self.group_id {
+            v.object_id("group_id", group_id);
+        }
+        if let Some(parent_id) = &self.parent_id {
+            v.object_id("parent_id", parent_id);
+        }
+        v.finish()
+    }
+}
+
+impl Validate for BreedUpdate {
+    fn validate(&self) -> Result<(), Error> {
+        let mut v = Validator::default();
+        if let Some(name) = &self.name {
+            v.text("name", name, 1, MAX_NAME_LEN);
+        }
+        for (locale, name) in self.names.iter().flatten() {
+            v.text(&format!("names.{}", locale), name, 1, MAX_NAME_LEN);
+        }
+        if let Some(aliases) = &self.aliases {
+            v.aliases("aliases", aliases);
+        }
+        if let Some(attributes) = &self.attributes {
+            v.attributes("attributes", attributes);
+        }
+        if let Some(Some(group_id)) = &self.group_id {
+            v.object_id("group_id", group_id);
+        }
+        if let Some(Some(parent_id)) = &self.parent_id {
+            v.object_id("parent_id", parent_id);
+        }
+        v.finish()
+    }
+}
+
+impl Validate for WebhookCreate {
+    fn validate(&self) -> Result<(), Error> {
+        let mut v = Validator::default();
+        let url = self.url.to_lowercase();
+        v.check(
+            url.starts_with("http://") || url.starts_with("https://"),
+            "url",
+            "must be an http or https url",
+        );
+        v.text("url", &self.url, 1, MAX_URL_LEN);
+        v.check(!self.event_types.is_empty(), "event_types", "must not be empty");
+        let secret_len = self.secret.chars().count();
+        v.check(
+            (MIN_SECRET_LEN..=MAX_SECRET_LEN).contains(&secret_len),
+            "secret",
+            format!("must be between {} and {} characters", MIN_SECRET_LEN, MAX_SECRET_LEN),
+        );
+        for (i, dog_id) in self.dog_ids.iter().enumerate() {
+            v.object_id(&format!("dog_ids[{}]", i), dog_id);
+        }
+        v.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, NaiveDate};
+
+    use super::*;
+    use crate::core::entities::{BirthdayPrecision, Gender};
+
+    fn fields(res: Result<(), Error>) -> Vec<String> {
+        let e = res.unwrap_err();
+        assert_eq!(e.kind(), crate::core::error::ErrorKind::InvalidInput);
+        e.details().unwrap()["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["field"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    fn breed(id: &str) -> BreedQuery {
+        BreedQuery {
+            id: Some(id.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn component(id: &str, percentage: Option<u8>) -> BreedComponentCreate {
+        BreedComponentCreate {
+            breed: breed(id),
+            percentage,
+        }
+    }
+
+    fn dog() -> DogCreate {
+        DogCreate {
+            owner_id: "user-1".to_owned(),
+            name: "不二".to_owned(),
+            gender: Gender::Male,
+            breed: breed("6534c1e563e5adcdbf8a3790"),
+            breed_components: vec![],
+            unknown_mix: false,
+            birthday: Birthday::new(NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(), BirthdayPrecision::Day),
+            is_sterilized: false,
+            introduction: String::new(),
+            tags: vec![],
+            portrait_id: None,
+        }
+    }
+
+    #[test]
+    fn text_counts_characters_and_rejects_blank_and_control_characters() {
+        let mut v = Validator::default();
+        v.text("a", "   ", 1, 4);
+        v.text("b", "汪汪汪汪", 1, 4);
+        v.text("c", "汪汪汪汪汪", 1, 4);
+        v.text("d", "汪\n", 0, 4);
+        v.text("e", "", 0, 4);
+        v.text("f", "汪\t", 0, 4);
+        assert_eq!(fields(v.finish()), ["a", "c", "d", "f"]);
+    }
+
+    #[test]
+    fn multiline_text_allows_line_breaks_and_tabs_only() {
+        let mut v = Validator::default();
+        v.multiline_text("a", "第一行\r\n\t第二行", 0, 20);
+        v.multiline_text("b", "汪\u{0}", 0, 20);
+        v.multiline_text("c", "汪\u{1b}[31m", 0, 20);
+        v.multiline_text("d", "\n\n", 1, 20);
+        assert_eq!(fields(v.finish()), ["b", "c", "d"]);
+        let dog = DogCreate {
+            introduction: "很乖\n不咬人".to_owned(),
+            ..dog()
+        };
+        assert!(dog.validate().is_ok());
+        assert_eq!(
+            fields(
+                DogCreate {
+                    name: "不\n二".to_owned(),
+                    ..dog
+                }
+                .validate()
+            ),
+            ["name"]
+        );
+    }
+
+    #[test]
+    fn ids_and_object_ids() {
+        let mut v = Validator::default();
+        v.id("a", "user_1-A");
+        v.id("b", "");
+        v.id("c", "user 1");
+        v.id("d", &"x".repeat(MAX_ID_LEN + 1));
+        v.object_id("e", "6534c1e563e5adcdbf8a3790");
+        v.object_id("f", "6534c1e563e5adcdbf8a379");
+        v.object_id("g", "6534c1e563e5adcdbf8a379z");
+        assert_eq!(fields(v.finish()), ["b", "c", "d", "f", "g"]);
+    }
+
+    #[test]
+    fn birthday_must_not_be_in_the_future_or_too_old() {
+        let today = Utc::now().date_naive();
+        let mut v = Validator::default();
+        v.birthday("a", &Birthday::new(today, BirthdayPrecision::Day));
+        v.birthday("b", &Birthday::new(today + Duration::days(1), BirthdayPrecision::Day));
+        v.birthday(
+            "c",
+            &Birthday::new(today - Duration::days(MAX_AGE_YEARS * 366 + 400), BirthdayPrecision::Year),
+        );
+        // 估计的生日可能早于今天即可
+        v.birthday("d", &Birthday::new(today + Duration::days(30), BirthdayPrecision::Estimated));
+        assert_eq!(fields(v.finish()), ["b", "c"]);
+    }
+
+    #[test]
+    fn tags_must_be_unique_and_without_separators() {
+        let mut v = Validator::default();
+        v.tags("tags", &["乖".to_owned(), "a b".to_owned(), "a,b".to_owned(), "乖".to_owned()]);
+        assert_eq!(fields(v.finish()), ["tags[1]", "tags[2]", "tags[3]"]);
+        let mut v = Validator::default();
+        v.tags("tags", &(0..=MAX_TAGS).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert_eq!(fields(v.finish()), ["tags"]);
+    }
+
+    #[test]
+    fn breed_components_percentages() {
+        let a = "6534c1e563e5adcdbf8a3790";
+        let b = "6534c1e563e5adcdbf8a3791";
+        let check = |components: &[BreedComponentCreate], unknown_mix: bool| {
+            let mut v = Validator::default();
+            v.breed_components("c", components, unknown_mix);
+            v.finish().err().map(|e| fields(Err(e))).unwrap_or_default()
+        };
+        assert!(check(&[component(a, Some(50)), component(b, Some(50))], false).is_empty());
+        assert!(check(&[component(a, Some(67)), component(b, Some(32))], false).is_empty());
+        assert!(check(&[component(a, Some(50)), component(b, None)], false).is_empty());
+        assert!(check(&[component(a, Some(50)), component(b, Some(20))], true).is_empty());
+        assert_eq!(check(&[component(a, Some(50)), component(b, Some(20))], false), ["c"]);
+        assert_eq!(check(&[component(a, Some(80)), component(b, Some(30))], true), ["c"]);
+        assert_eq!(
+            check(&[component(a, Some(0)), component(a, None)], true),
+            ["c[0].percentage", "c[1].breed"]
+        );
+        assert_eq!(
+            check(
+                &[BreedComponentCreate {
+                    breed: BreedQuery::default(),
+                    percentage: None
+                }],
+                true
+            ),
+            ["c[0].breed.id"]
+        );
+    }
+
+    #[test]
+    fn dog_create_collects_all_field_errors() {
+        assert!(dog().validate().is_ok());
+        let invalid = DogCreate {
+            owner_id: String::new(),
+            name: String::new(),
+            breed: BreedQuery::default(),
+            tags: vec!["a b".to_owned()],
+            portrait_id: Some("../etc".to_owned()),
+            ..dog()
+        };
+        assert_eq!(fields(invalid.validate()), ["owner_id", "name", "breed.id", "tags[0]", "portrait_id"]);
+    }
+
+    #[test]
+    fn dog_update_only_checks_present_fields() {
+        assert!(DogUpdate::default().validate().is_ok());
+        let update = DogUpdate {
+            name: Some(" ".to_owned()),
+            introduction: Some(None),
+            portrait_id: Some(None),
+            ..Default::default()
+        };
+        assert_eq!(fields(update.validate()), ["name"]);
+    }
+
+    #[test]
+    fn dog_update_checks_component_sum_only_with_a_known_unknown_mix() {
+        let a = "6534c1e563e5adcdbf8a3790";
+        let b = "6534c1e563e5adcdbf8a3791";
+        let components = vec![component(a, Some(50)), component(b, Some(20))];
+        let update = DogUpdate {
+            breed_components: Some(components.clone()),
+            ..Default::default()
+        };
+        assert!(update.validate().is_ok());
+        let update = DogUpdate {
+            unknown_mix: Some(false),
+            ..update
+        };
+        assert_eq!(fields(update.validate()), ["breed_components"]);
+        let update = DogUpdate {
+            breed_components: Some(vec![component(a, Some(80)), component(b, Some(30))]),
+            unknown_mix: None,
+            ..Default::default()
+        };
+        assert_eq!(fields(update.validate()), ["breed_components"]);
+    }
+
+    #[test]
+    fn breed_update_checks_hierarchy_ids_unless_cleared() {
+        let update = BreedUpdate {
+            group_id: Some(None),
+            parent_id: Some(None),
+            ..Default::default()
+        };
+        assert!(update.validate().is_ok());
+        let update = BreedUpdate {
+            group_id: Some(Some("g1".to_owned())),
+            parent_id: Some(Some("6534c1e563e5adcdbf8a3790".to_owned())),
+            ..Default::default()
+        };
+        assert_eq!(fields(update.validate()), ["group_id"]);
+    }
+
+    #[test]
+    fn webhook_create_requires_http_url_event_types_and_secret() {
+        let webhook = WebhookCreate {
+            url: "ftp://example.com".to_owned(),
+            event_types: vec![],
+            secret: "short".to_owned(),
+            dog_ids: vec!["1".to_owned()],
+        };
+        assert_eq!(fields(webhook.validate()), ["url", "event_types", "secret", "dog_ids[0]"]);
+    }
+}
@@ -0,0 +1,344 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use futures::future::LocalBoxFuture;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::core::{
+    error::Error,
+    events::{DomainEvent, EventSink, EventType},
+    repository::{Pagination, Repository},
+    service::Service,
+};
+
+// 请求头格式: "t=<Unix秒>,v1=<十六进制HMAC-SHA256>", 签名内容为"<t>.<请求体>"
+// 接收方用订阅时提供的secret重新计算并比较, 同时检查t防止重放
+pub const SIGNATURE_HEADER: &str = "X-Little-Walk-Signature";
+pub const EVENT_HEADER: &str = "X-Little-Walk-Event";
+pub const DELIVERY_HEADER: &str = "X-Little-Walk-Delivery";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookCreate {
+    pub url: String,
+    pub event_types: Vec<EventType>,
+    pub secret: String,
+    #[serde(default)]
+    pub dog_ids: Vec<String>, // 只推送这些狗狗的事件, 为空时推送所有狗狗的事件
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    pub event_types: Vec<EventType>,
+    #[serde(default, skip_serializing)]
+    pub secret: String, // 只在创建时提供, 不再返回
+    pub dog_ids: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    // 品种事件不受dog_ids限制
+    pub fn matches(&self, event: &DomainEvent) -> bool {
+        self.event_types.contains(&event.event_type)
+            && (self.dog_ids.is_empty() || event.event_type == EventType::BreedChanged || self.dog_ids.contains(&event.aggregate_id))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Dead, // 超过最大投递次数, 只能手动重新投递
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    #[serde(default)]
+    pub id: String,
+    pub webhook_id: String,
+    pub event: DomainEvent,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+impl WebhookDelivery {
+    pub fn new(webhook_id: &str, event: &DomainEvent) -> Self {
+        let now = Utc::now();
+        Self {
+            id: String::new(),
+            webhook_id: webhook_id.to_owned(),
+            event: event.clone(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            created_at: now,
+            delivered_at: None,
+        }
+    }
+
+    pub fn succeeded(&mut self) {
+        self.attempts += 1;
+        self.status = DeliveryStatus::Delivered;
+        self.last_error = None;
+        self.delivered_at = Some(Utc::now());
+    }
+
+    // 手动重新投递, 重新计算投递次数, 立即投递
+    pub fn redeliver(&mut self) {
+        self.status = DeliveryStatus::Pending;
+        self.attempts = 0;
+        self.next_attempt_at = Utc::now();
+        self.last_error = None;
+        self.delivered_at = None;
+    }
+
+    // 按指数退避安排下次投递, 达到最大次数后进入死信
+    pub fn failed(&mut self, error: String, max_attempts: u32) {
+        self.attempts += 1;
+        self.last_error = Some(error);
+        if self.attempts >= max_attempts {
+            self.status = DeliveryStatus::Dead;
+        } else {
+            self.next_attempt_at = Utc::now() + backoff(self.attempts);
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct WebhookDeliveryQuery {
+    pub webhook_id: Option<String>,
+    pub status: Option<DeliveryStatus>,
+    pub pagination: Option<Pagination>,
+}
+
+// 第n次失败后等待30秒 * 2^(n-1), 最长1小时
+pub fn backoff(attempts: u32) -> Duration {
+    let seconds = 30_i64.saturating_mul(1 << attempts.saturating_sub(1).min(16));
+    Duration::seconds(seconds.min(3600))
+}
+
+pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// 投递一次, 非2xx的响应视为失败, 返回失败原因
+pub async fn send(client: &reqwest::Client, webhook: &Webhook, delivery: &WebhookDelivery) -> Result<(), String> {
+    let body = serde_json::to_vec(&delivery.event).map_err(|e| e.to_string())?;
+    let timestamp = Utc::now().timestamp();
+    let res = client
+        .post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .header(EVENT_HEADER, format!("{:?}", delivery.event.event_type))
+        .header(DELIVERY_HEADER, &delivery.id)
+        .header(
+            SIGNATURE_HEADER,
+            format!("t={},v1={}", timestamp, signature(&webhook.secret, timestamp, &body)),
+        )
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("unexpected status {}", res.status()))
+    }
+}
+
+// 把事件按订阅展开为待投递记录, 由Service::deliver_webhooks实际发送
+pub struct WebhookSink<R>
+where
+    R: Repository,
+{
+    service: Arc<Service<R>>,
+}
+
+impl<R> WebhookSink<R>
+where
+    R: Repository,
+{
+    pub fn new(service: Arc<Service<R>>) -> Self {
+        Self { service }
+    }
+}
+
+impl<R> EventSink for WebhookSink<R>
+where
+    R: Repository,
+{
+    fn publish<'a>(&'a self, events: &'a [DomainEvent]) -> LocalBoxFuture<'a, Result<(), Error>> {
+        Box::pin(self.service.enqueue_webhooks(events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread::JoinHandle,
+    };
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn event(event_type: EventType, aggregate_id: &str) -> DomainEvent {
+        DomainEvent::new(event_type, aggregate_id, Some("user-1"), 1, json!({}))
+    }
+
+    fn webhook(url: &str, event_types: Vec<EventType>, dog_ids: Vec<&str>) -> Webhook {
+        Webhook {
+            id: "webhook-1".to_owned(),
+            url: url.to_owned(),
+            event_types,
+            secret: "s3cret".to_owned(),
+            dog_ids: dog_ids.into_iter().map(str::to_owned).collect(),
+            created_at: Utc::now(),
+        }
+    }
+
+    // 请求头(名称小写)和请求体
+    type Received = (Vec<(String, String)>, Vec<u8>);
+
+    // 只接收一个请求的HTTP服务, 以status响应
+    fn receiver(status: u16) -> (String, JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut headers = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    headers.push((name.trim().to_lowercase(), value.trim().to_owned()));
+                }
+            }
+            let len = headers.iter().find(|(n, _)| n == "content-length").map_or(0, |(_, v)| v.parse().unwrap());
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            (headers, body)
+        });
+        (url, handle)
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers.iter().find(|(n, _)| n == &name.to_lowercase()).map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn signature_is_hmac_sha256_of_timestamp_and_body() {
+        // echo -n '1700000000.{"a":1}' | openssl dgst -sha256 -hmac s3cret
+        assert_eq!(
+            signature("s3cret", 1700000000, br#"{"a":1}"#),
+            "1698a50bc74d1ff1db85c4e0a5297c2ad9fdba245d5737cdb789e4cc6e098940"
+        );
+        assert_ne!(
+            signature("s3cret", 1700000001, br#"{"a":1}"#),
+            signature("s3cret", 1700000000, br#"{"a":1}"#)
+        );
+        assert_ne!(
+            signature("other", 1700000000, br#"{"a":1}"#),
+            signature("s3cret", 1700000000, br#"{"a":1}"#)
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_one_hour() {
+        let seconds = [1, 2, 3, 4, 7, 8, 100].map(|n| backoff(n).num_seconds());
+        assert_eq!(seconds, [30, 60, 120, 240, 1920, 3600, 3600]);
+        assert_eq!(backoff(0).num_seconds(), 30);
+    }
+
+    #[test]
+    fn matches_filters_by_event_type_and_dog() {
+        let all = webhook("", vec![EventType::DogUpdated, EventType::BreedChanged], vec![]);
+        assert!(all.matches(&event(EventType::DogUpdated, "dog-1")));
+        assert!(!all.matches(&event(EventType::DogDeleted, "dog-1")));
+        let some = webhook("", vec![EventType::DogUpdated, EventType::BreedChanged], vec!["dog-1"]);
+        assert!(some.matches(&event(EventType::DogUpdated, "dog-1")));
+        assert!(!some.matches(&event(EventType::DogUpdated, "dog-2")));
+        // 品种事件不受dog_ids限制
+        assert!(some.matches(&event(EventType::BreedChanged, "breed-1")));
+    }
+
+    #[test]
+    fn failed_delivery_backs_off_then_dies() {
+        let mut delivery = WebhookDelivery::new("webhook-1", &event(EventType::DogUpdated, "dog-1"));
+        delivery.failed("timeout".to_owned(), 3);
+        assert_eq!((delivery.status, delivery.attempts), (DeliveryStatus::Pending, 1));
+        assert!(delivery.next_attempt_at > Utc::now() + Duration::seconds(25));
+        delivery.failed("timeout".to_owned(), 3);
+        delivery.failed("unexpected status 500".to_owned(), 3);
+        assert_eq!((delivery.status, delivery.attempts), (DeliveryStatus::Dead, 3));
+        assert_eq!(delivery.last_error.as_deref(), Some("unexpected status 500"));
+    }
+
+    #[test]
+    fn redeliver_resets_dead_delivery() {
+        let mut delivery = WebhookDelivery::new("webhook-1", &event(EventType::DogUpdated, "dog-1"));
+        delivery.failed("timeout".to_owned(), 1);
+        assert_eq!(delivery.status, DeliveryStatus::Dead);
+        delivery.redeliver();
+        assert_eq!(
+            (delivery.status, delivery.attempts, delivery.last_error.as_deref()),
+            (DeliveryStatus::Pending, 0, None)
+        );
+        assert!(delivery.next_attempt_at <= Utc::now());
+        delivery.succeeded();
+        assert_eq!((delivery.status, delivery.attempts), (DeliveryStatus::Delivered, 1));
+        assert!(delivery.delivered_at.is_some());
+    }
+
+    #[actix_web::test]
+    async fn send_signs_request_for_receiver() {
+        let (url, handle) = receiver(204);
+        let hook = webhook(&url, vec![EventType::DogUpdated], vec![]);
+        let mut delivery = WebhookDelivery::new(&hook.id, &event(EventType::DogUpdated, "dog-1"));
+        delivery.id = "delivery-1".to_owned();
+        send(&reqwest::Client::new(), &hook, &delivery).await.unwrap();
+        let (headers, body) = handle.join().unwrap();
+        assert_eq!(header(&headers, EVENT_HEADER), Some("DogUpdated"));
+        assert_eq!(header(&headers, DELIVERY_HEADER), Some("delivery-1"));
+        let (t, v1) = header(&headers, SIGNATURE_HEADER).unwrap().split_once(',').unwrap();
+        let t = t.strip_prefix("t=").unwrap().parse::<i64>().unwrap();
+        assert_eq!(v1.strip_prefix("v1=").unwrap(), signature("s3cret", t, &body));
+        assert_eq!(serde_json::from_slice::<DomainEvent>(&body).unwrap().aggregate_id, "dog-1");
+    }
+
+    #[actix_web::test]
+    async fn send_fails_on_error_status() {
+        let (url, handle) = receiver(500);
+        let hook = webhook(&url, vec![EventType::DogUpdated], vec![]);
+        let mut delivery = WebhookDelivery::new(&hook.id, &event(EventType::DogUpdated, "dog-1"));
+        let err = send(&reqwest::Client::new(), &hook, &delivery).await.unwrap_err();
+        handle.join().unwrap();
+        assert!(err.contains("500"), "{}", err);
+        delivery.failed(err, 1);
+        assert_eq!(delivery.status, DeliveryStatus::Dead);
+    }
+}
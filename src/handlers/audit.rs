@@ -0,0 +1,21 @@
+use crate::{
+    core::{
+        audit::{AuditEntry, AuditQuery},
+        repository::Repository,
+        service::Service,
+    },
+    handlers::common::http_error,
+};
+use actix_web::{
+    web::{Data, Json},
+    Error,
+};
+use nb_serde_query::actix_web::Query;
+
+// 按狗狗/品种id或操作人查询, 如?entity=dog&entity_id=xxx, ?actor=xxx
+pub async fn audit_log<R>(service: Data<Service<R>>, Query(query): Query<AuditQuery>) -> Result<Json<Vec<AuditEntry>>, Error>
+where
+    R: Repository,
+{
+    service.audit_log(&query).await.map(Json).map_err(http_error)
+}
@@ -1,28 +1,151 @@
 use crate::{
     core::{
+        breed_import::{self, ImportFormat, ImportReport},
         entities::Breed,
-        repository::{BreedCreate, BreedQuery, Repository},
+        locale::Localize,
+        repository::{BreedCreate, BreedQuery, BreedUpdate, Repository},
         service::Service,
     },
-    handlers::common::ListResp,
+    handlers::common::{http_error, not_modified, with_etag, with_localized_etag, AcceptLanguage, HeaderUserID, IfMatch, ListResp},
 };
 use actix_web::{
-    error::ErrorInternalServerError,
-    web::{Data, Json, Query},
-    Error,
+    web::{Bytes, Data, Json, Path, Query},
+    Error, HttpRequest, HttpResponse,
 };
+use serde::{Deserialize, Serialize};
 
-pub(crate) async fn create_breed<R>(service: Data<Service<R>>, Json(breed): Json<BreedCreate>) -> Result<String, Error>
+pub(crate) async fn create_breed<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    Json(breed): Json<BreedCreate>,
+) -> Result<String, Error>
 where
     R: Repository,
 {
-    service.create_breed(breed).await.map_err(ErrorInternalServerError)
+    service.create_breed(&actor, breed).await.map_err(http_error)
 }
 
-pub(crate) async fn breeds<R>(service: Data<Service<R>>, Query(query): Query<BreedQuery>) -> Result<Json<ListResp<Breed>>, Error>
+#[derive(Debug, Serialize)]
+pub struct UpdateBreedResult {
+    pub updated: bool,
+}
+
+pub async fn update_breed<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    IfMatch(version): IfMatch,
+    id: Path<(String,)>,
+    Json(breed): Json<BreedUpdate>,
+) -> Result<HttpResponse, Error>
+where
+    R: Repository,
+{
+    let updated = service.update_breed(&actor, &id.0, &breed, version).await.map_err(http_error)?;
+    let current = service.breed(&id.0).await.map_err(http_error)?;
+    Ok(with_etag(current.version, &UpdateBreedResult { updated }))
+}
+
+pub async fn breed<R>(
+    service: Data<Service<R>>,
+    AcceptLanguage(locale): AcceptLanguage,
+    req: HttpRequest,
+    id: Path<(String,)>,
+) -> Result<HttpResponse, Error>
+where
+    R: Repository,
+{
+    let mut breed = service.breed(&id.0).await.map_err(http_error)?;
+    if let Some(resp) = not_modified(&req, breed.version, locale) {
+        return Ok(resp);
+    }
+    breed.localize(locale);
+    Ok(with_localized_etag(breed.version, locale, &breed))
+}
+
+pub(crate) async fn breeds<R>(
+    service: Data<Service<R>>,
+    AcceptLanguage(locale): AcceptLanguage,
+    Query(query): Query<BreedQuery>,
+) -> Result<Json<ListResp<Breed>>, Error>
 where
     R: Repository,
 {
-    let (breeds, total) = service.query_breeds(&query).await.map_err(ErrorInternalServerError)?;
+    let (mut breeds, total) = service.query_breeds(&query).await.map_err(http_error)?;
+    breeds.localize(locale);
     Ok(Json(ListResp::new(breeds, total)))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct SearchBreedsReq {
+    keyword: String,
+    limit: Option<usize>,
+}
+
+pub async fn search_breeds<R>(
+    service: Data<Service<R>>,
+    AcceptLanguage(locale): AcceptLanguage,
+    Query(query): Query<SearchBreedsReq>,
+) -> Result<Json<Vec<Breed>>, Error>
+where
+    R: Repository,
+{
+    let limit = query.limit.unwrap_or(10).min(50);
+    let mut breeds = service.search_breeds(&query.keyword, limit).await.map_err(http_error)?;
+    breeds.localize(locale);
+    Ok(Json(breeds))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportBreedsReq {
+    format: Option<ImportFormat>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+// 未指定format时根据Content-Type判断, 默认为JSON
+pub async fn import_breeds<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    req: HttpRequest,
+    Query(query): Query<ImportBreedsReq>,
+    body: Bytes,
+) -> Result<Json<ImportReport>, Error>
+where
+    R: Repository,
+{
+    let format = query
+        .format
+        .unwrap_or_else(|| match req.headers().get("Content-Type").and_then(|hv| hv.to_str().ok()) {
+            Some(ct) if ct.contains("csv") => ImportFormat::Csv,
+            _ => ImportFormat::Json,
+        });
+    let rows = breed_import::parse(&body, format).map_err(http_error)?;
+    service.import_breeds(&actor, rows, query.dry_run).await.map(Json).map_err(http_error)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeBreedReq {
+    into: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MergeBreedResp {
+    merged_into: String,
+    dogs_repointed: u64,
+}
+
+pub async fn merge_breed<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    id: Path<(String,)>,
+    Json(req): Json<MergeBreedReq>,
+) -> Result<Json<MergeBreedResp>, Error>
+where
+    R: Repository,
+{
+    let dogs_repointed = service.merge_breeds(&actor, &id.0, &req.into).await.map_err(http_error)?;
+    Ok(Json(MergeBreedResp {
+        merged_into: req.into,
+        dogs_repointed,
+    }))
+}
@@ -0,0 +1,42 @@
+use crate::{
+    core::{
+        entities::{Breed, BreedGroup},
+        locale::Localize,
+        repository::{BreedGroupCreate, Repository},
+        service::Service,
+    },
+    handlers::common::{http_error, AcceptLanguage, ListResp},
+};
+use actix_web::{
+    web::{Data, Json, Path},
+    Error,
+};
+
+pub async fn create_breed_group<R>(service: Data<Service<R>>, Json(group): Json<BreedGroupCreate>) -> Result<String, Error>
+where
+    R: Repository,
+{
+    service.create_breed_group(&group).await.map_err(http_error)
+}
+
+pub async fn breed_groups<R>(service: Data<Service<R>>, AcceptLanguage(locale): AcceptLanguage) -> Result<Json<Vec<BreedGroup>>, Error>
+where
+    R: Repository,
+{
+    let mut groups = service.breed_groups().await.map_err(http_error)?;
+    groups.localize(locale);
+    Ok(Json(groups))
+}
+
+pub async fn group_breeds<R>(
+    service: Data<Service<R>>,
+    AcceptLanguage(locale): AcceptLanguage,
+    group_id: Path<(String,)>,
+) -> Result<Json<ListResp<Breed>>, Error>
+where
+    R: Repository,
+{
+    let (mut breeds, total) = service.group_breeds(&group_id.0).await.map_err(http_error)?;
+    breeds.localize(locale);
+    Ok(Json(ListResp::new(breeds, total)))
+}
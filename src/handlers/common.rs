@@ -1,6 +1,56 @@
-use actix_web::{error::ErrorBadRequest, Error, FromRequest};
+use actix_web::{
+    error::{ErrorBadRequest, ErrorPreconditionFailed, InternalError, JsonPayloadError},
+    http::{
+        header::{self, EntityTag, IfNoneMatch},
+        StatusCode,
+    },
+    web::Data,
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
 use futures::future::{err, ok, Ready};
 use serde::Serialize;
+use serde_json::Value;
+
+use crate::core::{
+    error::{self, ErrorKind},
+    locale::Locale,
+};
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResp<'a> {
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<&'a Value>,
+}
+
+// 按错误类型转换为对应的HTTP状态码, 响应体为JSON
+pub fn http_error(e: error::Error) -> Error {
+    let status = match e.kind() {
+        ErrorKind::InvalidInput => StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorKind::Conflict => StatusCode::CONFLICT,
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+        ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let resp = HttpResponse::build(status).json(ErrorResp {
+        message: e.to_string(),
+        details: e.details(),
+    });
+    InternalError::from_response(e, resp).into()
+}
+
+// 请求体格式正确但字段值非法(如无法识别的性别)时返回422, 其他情况返回400
+pub fn json_error_handler(err: JsonPayloadError, _: &HttpRequest) -> Error {
+    let status = match &err {
+        JsonPayloadError::Deserialize(e) if e.is_data() => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    let resp = HttpResponse::build(status).json(ErrorResp {
+        message: err.to_string(),
+        details: None,
+    });
+    InternalError::from_response(err, resp).into()
+}
 
 pub struct HeaderUserID(pub String);
 
@@ -21,6 +71,25 @@ impl FromRequest for HeaderUserID {
     }
 }
 
+// 从Accept-Language中选择语言, 无法识别时使用app_data中配置的默认语言
+pub struct AcceptLanguage(pub Locale);
+
+impl FromRequest for AcceptLanguage {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let locale = req
+            .headers()
+            .get("Accept-Language")
+            .and_then(|hv| hv.to_str().ok())
+            .and_then(Locale::from_accept_language)
+            .or_else(|| req.app_data::<Data<Locale>>().map(|l| *l.get_ref()))
+            .unwrap_or_default();
+        ok(AcceptLanguage(locale))
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct ListResp<T>
 where
@@ -38,3 +107,138 @@ where
         Self { list, total }
     }
 }
+
+// 以版本号作为强ETag
+pub fn etag(version: u64) -> EntityTag {
+    EntityTag::new_strong(version.to_string())
+}
+
+// 响应体按语言翻译时, ETag带上语言, 如"3-zh", 不同语言的表示不会互相命中缓存
+pub fn localized_etag(version: u64, locale: Locale) -> EntityTag {
+    EntityTag::new_strong(format!("{}-{}", version, locale.tag()))
+}
+
+// 响应体附带ETag
+pub fn with_etag<T>(version: u64, body: &T) -> HttpResponse
+where
+    T: Serialize,
+{
+    HttpResponse::Ok().insert_header(header::ETag(etag(version))).json(body)
+}
+
+// 响应体附带带语言的ETag, 并声明随Accept-Language变化
+pub fn with_localized_etag<T>(version: u64, locale: Locale, body: &T) -> HttpResponse
+where
+    T: Serialize,
+{
+    HttpResponse::Ok()
+        .insert_header(header::ETag(localized_etag(version, locale)))
+        .insert_header((header::VARY, "Accept-Language"))
+        .json(body)
+}
+
+// If-None-Match与当前版本和语言一致时返回304
+pub fn not_modified(req: &HttpRequest, version: u64, locale: Locale) -> Option<HttpResponse> {
+    let current = localized_etag(version, locale);
+    let matched = match req.get_header::<IfNoneMatch>()? {
+        IfNoneMatch::Any => true,
+        IfNoneMatch::Items(tags) => tags.iter().any(|t| t.weak_eq(&current)),
+    };
+    matched.then(|| {
+        HttpResponse::NotModified()
+            .insert_header(header::ETag(current))
+            .insert_header((header::VARY, "Accept-Language"))
+            .finish()
+    })
+}
+
+// If-Match中的版本号, 缺省或为*时不检查版本, 弱ETag和无法识别的ETag不可能匹配, 直接返回412
+// 带语言的ETag只取版本号部分, 客户端可直接回传GET得到的ETag
+pub struct IfMatch(pub Option<u64>);
+
+impl FromRequest for IfMatch {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        if !req.headers().contains_key(header::IF_MATCH) {
+            return ok(IfMatch(None));
+        }
+        match req.get_header::<header::IfMatch>() {
+            Some(header::IfMatch::Any) => ok(IfMatch(None)),
+            Some(header::IfMatch::Items(tags)) => match tags.iter().find(|t| !t.weak).and_then(|t| tag_version(t.tag())) {
+                Some(version) => ok(IfMatch(Some(version))),
+                None => err(ErrorPreconditionFailed("etag not matched")),
+            },
+            None => err(ErrorBadRequest("invalid If-Match header")),
+        }
+    }
+}
+
+fn tag_version(tag: &str) -> Option<u64> {
+    tag.split('-').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    async fn if_match(value: Option<&str>) -> Result<Option<u64>, StatusCode> {
+        let mut req = TestRequest::default();
+        if let Some(value) = value {
+            req = req.insert_header((header::IF_MATCH, value));
+        }
+        let (req, mut payload) = req.to_http_parts();
+        IfMatch::from_request(&req, &mut payload)
+            .await
+            .map(|m| m.0)
+            .map_err(|e| e.as_response_error().status_code())
+    }
+
+    fn if_none_match(value: &str) -> HttpRequest {
+        TestRequest::default().insert_header((header::IF_NONE_MATCH, value)).to_http_request()
+    }
+
+    #[actix_web::test]
+    async fn if_match_parses_the_version() {
+        assert_eq!(if_match(None).await, Ok(None));
+        assert_eq!(if_match(Some("*")).await, Ok(None));
+        assert_eq!(if_match(Some("\"3\"")).await, Ok(Some(3)));
+        assert_eq!(if_match(Some("\"3-en\"")).await, Ok(Some(3)));
+        assert_eq!(if_match(Some("W/\"3\"")).await, Err(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(if_match(Some("\"abc\"")).await, Err(StatusCode::PRECONDITION_FAILED));
+    }
+
+    #[test]
+    fn localized_etag_includes_the_locale() {
+        assert_eq!(etag(3).to_string(), "\"3\"");
+        assert_eq!(localized_etag(3, Locale::Zh).to_string(), "\"3-zh\"");
+        assert_ne!(localized_etag(3, Locale::Zh), localized_etag(3, Locale::En));
+    }
+
+    #[test]
+    fn with_localized_etag_varies_by_language() {
+        let resp = with_localized_etag(3, Locale::En, &());
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"3-en\"");
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept-Language");
+    }
+
+    #[test]
+    fn not_modified_matches_version_and_locale() {
+        // 缺省
+        assert!(not_modified(&TestRequest::default().to_http_request(), 3, Locale::Zh).is_none());
+        // 一致, 弱比较
+        let resp = not_modified(&if_none_match("\"3-zh\""), 3, Locale::Zh).unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"3-zh\"");
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept-Language");
+        assert!(not_modified(&if_none_match("W/\"3-zh\""), 3, Locale::Zh).is_some());
+        assert!(not_modified(&if_none_match("*"), 3, Locale::Zh).is_some());
+        // 过期版本或其他语言
+        assert!(not_modified(&if_none_match("\"2-zh\""), 3, Locale::Zh).is_none());
+        assert!(not_modified(&if_none_match("\"3-en\""), 3, Locale::Zh).is_none());
+        assert!(not_modified(&if_none_match("\"3\""), 3, Locale::Zh).is_none());
+    }
+}
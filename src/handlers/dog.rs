@@ -1,16 +1,24 @@
 use crate::core::{
+    self,
+    batch::{self, BatchReport, BatchReq},
     entities::Dog,
-    repository::{DogCreate, DogQuery, DogUpdate, Pagination, Repository},
+    events::InProcessSink,
+    feed::DogNotification,
+    locale::Localize,
+    repository::{DogCreate, DogQuery, DogUpdate, DogVersion, Pagination, Repository},
     service::Service,
+    sync::DogSync,
 };
 use actix_web::{
-    error::ErrorInternalServerError,
-    web::{Data, Json, Path},
-    Error,
+    error::ErrorUnsupportedMediaType,
+    web::{Bytes, Data, Json, Path},
+    Error, HttpRequest, HttpResponse,
 };
+use futures::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
 
-use super::common::HeaderUserID;
+use super::common::{http_error, not_modified, with_localized_etag, AcceptLanguage, HeaderUserID, IfMatch};
 use nb_serde_query::actix_web::Query;
 
 #[derive(Debug, Serialize)]
@@ -18,39 +26,286 @@ pub struct CreateDogResult {
     pub id: String,
 }
 
-pub async fn create_dog<R>(serive: Data<Service<R>>, Json(dog): Json<DogCreate>) -> Result<Json<Dog>, Error>
+pub async fn create_dog<R>(
+    serive: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    AcceptLanguage(locale): AcceptLanguage,
+    Json(dog): Json<DogCreate>,
+) -> Result<HttpResponse, Error>
 where
     R: Repository,
 {
-    serive.create_dog(&dog).await.map(Json).map_err(ErrorInternalServerError)
+    let mut dog = serive.create_dog(&actor, &dog).await.map_err(http_error)?;
+    dog.localize(locale);
+    Ok(with_localized_etag(dog.version, locale, &dog))
 }
 
-#[derive(Debug, Serialize)]
-pub struct UpdateDogResult {
-    pub updated: bool,
+// 每一项单独返回结果, atomic为true时有任一项失败则都不创建
+pub async fn create_dogs<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    Json(req): Json<BatchReq>,
+) -> Result<Json<BatchReport>, Error>
+where
+    R: Repository,
+{
+    service
+        .create_dogs(&actor, batch::parse(req.dogs), req.atomic)
+        .await
+        .map(Json)
+        .map_err(http_error)
 }
-pub async fn update_dog<R>(service: Data<Service<R>>, id: Path<(String,)>, Json(dog): Json<DogUpdate>) -> Result<Json<UpdateDogResult>, Error>
+
+// 每一项为{"id": "...", "version": 3, "dog": {...}}, dog与PATCH的merge patch相同
+pub async fn update_dogs<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    Json(req): Json<BatchReq>,
+) -> Result<Json<BatchReport>, Error>
 where
     R: Repository,
 {
-    service.update_dog(&id.0, &dog).await.map_err(ErrorInternalServerError).map(|updated| Json(UpdateDogResult { updated }))
+    service
+        .update_dogs(&actor, batch::parse(req.dogs), req.atomic)
+        .await
+        .map(Json)
+        .map_err(http_error)
 }
 
-pub async fn my_dogs<R>(service: Data<Service<R>>, HeaderUserID(uid): HeaderUserID, Query(pagination): Query<Pagination>) -> Result<Json<Vec<Dog>>, Error>
+pub async fn dog<R>(
+    service: Data<Service<R>>,
+    AcceptLanguage(locale): AcceptLanguage,
+    req: HttpRequest,
+    id: Path<(String,)>,
+) -> Result<HttpResponse, Error>
 where
     R: Repository,
 {
-    service.my_dogs(&uid, Some(pagination)).await.map_err(ErrorInternalServerError).map(Json)
+    let mut dog = service.dog(&id.0).await.map_err(http_error)?;
+    if let Some(resp) = not_modified(&req, dog.version, locale) {
+        return Ok(resp);
+    }
+    dog.localize(locale);
+    Ok(with_localized_etag(dog.version, locale, &dog))
 }
 
-pub async fn dogs<R>(service: Data<Service<R>>, Query(query): Query<DogQuery>) -> Result<Json<Vec<Dog>>, Error>
+// PUT为整体替换, 请求体与创建时相同
+pub async fn replace_dog<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    AcceptLanguage(locale): AcceptLanguage,
+    IfMatch(version): IfMatch,
+    id: Path<(String,)>,
+    Json(dog): Json<DogCreate>,
+) -> Result<HttpResponse, Error>
 where
     R: Repository,
 {
-    let dogs = service.query_dogs(&query).await.map_err(ErrorInternalServerError)?;
+    let mut dog = service.replace_dog(&actor, &id.0, &dog, version).await.map_err(http_error)?;
+    dog.localize(locale);
+    Ok(with_localized_etag(dog.version, locale, &dog))
+}
+
+pub async fn delete_dog<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    IfMatch(version): IfMatch,
+    id: Path<(String,)>,
+) -> Result<HttpResponse, Error>
+where
+    R: Repository,
+{
+    service.delete_dog(&actor, &id.0, version).await.map_err(http_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// 按Content-Type区分补丁格式:
+// application/json-patch+json => JSON Patch(RFC 6902)
+// application/merge-patch+json或application/json => JSON Merge Patch(RFC 7396)
+pub async fn patch_dog<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    AcceptLanguage(locale): AcceptLanguage,
+    IfMatch(version): IfMatch,
+    req: HttpRequest,
+    id: Path<(String,)>,
+    body: Bytes,
+) -> Result<HttpResponse, Error>
+where
+    R: Repository,
+{
+    let content_type = req.headers().get("Content-Type").and_then(|hv| hv.to_str().ok()).unwrap_or_default();
+    let mut dog = if content_type.starts_with("application/json-patch+json") {
+        let patch = serde_json::from_slice::<json_patch::Patch>(&body)
+            .map_err(|e| http_error(core::error::Error::invalid_input("invalid json patch").with_cause(e)))?;
+        service.patch_dog(&actor, &id.0, &patch, version).await.map_err(http_error)?
+    } else if content_type.starts_with("application/merge-patch+json") || content_type.starts_with("application/json") {
+        let update = serde_json::from_slice::<DogUpdate>(&body)
+            .map_err(|e| http_error(core::error::Error::invalid_input("invalid merge patch").with_cause(e)))?;
+        service.update_dog(&actor, &id.0, &update, version).await.map_err(http_error)?;
+        service.dog(&id.0).await.map_err(http_error)?
+    } else {
+        return Err(ErrorUnsupportedMediaType("unsupported patch format"));
+    };
+    dog.localize(locale);
+    Ok(with_localized_etag(dog.version, locale, &dog))
+}
+
+pub async fn my_dogs<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(uid): HeaderUserID,
+    AcceptLanguage(locale): AcceptLanguage,
+    Query(pagination): Query<Pagination>,
+) -> Result<Json<Vec<Dog>>, Error>
+where
+    R: Repository,
+{
+    let mut dogs = service.my_dogs(&uid, Some(pagination)).await.map_err(http_error)?;
+    dogs.localize(locale);
+    Ok(Json(dogs))
+}
+
+fn sse_message(event: &str, id: Option<&str>, data: &str) -> Bytes {
+    let id = id.map(|id| format!("id: {}\n", id)).unwrap_or_default();
+    Bytes::from(format!("{}event: {}\ndata: {}\n\n", id, event, data))
+}
+
+// Server-Sent Events: 推送自己的狗狗的created/updated/deleted通知, 每15秒发送一行注释保持连接
+// 推送跟不上时发送resync, 客户端应该用/apis/dogs/sync补齐, 断线重连后同样先同步一次
+pub async fn dog_events(events: Data<InProcessSink>, HeaderUserID(uid): HeaderUserID) -> HttpResponse {
+    let notifications = stream::unfold(events.subscribe(), move |mut receiver| {
+        let uid = uid.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => {
+                        let Some(notification) = DogNotification::for_user(&event, &uid) else {
+                            continue;
+                        };
+                        let data = serde_json::to_string(&notification).unwrap_or_default();
+                        return Some((sse_message(notification.change.name(), Some(&notification.event_id), &data), receiver));
+                    }
+                    Err(RecvError::Lagged(_)) => return Some((sse_message("resync", None, "{}"), receiver)),
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    let keep_alive = stream::unfold(
+        actix_web::rt::time::interval(std::time::Duration::from_secs(15)),
+        |mut interval| async move {
+            interval.tick().await;
+            Some((Bytes::from_static(b": keep-alive\n\n"), interval))
+        },
+    );
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream::select(notifications, keep_alive).map(Ok::<_, Error>))
+}
+
+pub async fn dogs<R>(
+    service: Data<Service<R>>,
+    AcceptLanguage(locale): AcceptLanguage,
+    Query(query): Query<DogQuery>,
+) -> Result<Json<Vec<Dog>>, Error>
+where
+    R: Repository,
+{
+    let mut dogs = service.query_dogs(&query).await.map_err(http_error)?;
+    dogs.localize(locale);
     Ok(Json(dogs))
 }
 
+pub async fn restore_dog<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(uid): HeaderUserID,
+    AcceptLanguage(locale): AcceptLanguage,
+    id: Path<(String,)>,
+) -> Result<HttpResponse, Error>
+where
+    R: Repository,
+{
+    let mut dog = service.restore_dog(&uid, &id.0, &uid).await.map_err(http_error)?;
+    dog.localize(locale);
+    Ok(with_localized_etag(dog.version, locale, &dog))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransferDogReq {
+    pub owner_id: String,
+}
+
+// 把自己的狗狗转让给owner_id, 可以带Idempotency-Key重试
+pub async fn transfer_dog<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(uid): HeaderUserID,
+    IfMatch(version): IfMatch,
+    AcceptLanguage(locale): AcceptLanguage,
+    id: Path<(String,)>,
+    Json(req): Json<TransferDogReq>,
+) -> Result<HttpResponse, Error>
+where
+    R: Repository,
+{
+    let mut dog = service
+        .transfer_dog(&uid, &id.0, &uid, &req.owner_id, version)
+        .await
+        .map_err(http_error)?;
+    dog.localize(locale);
+    Ok(with_localized_etag(dog.version, locale, &dog))
+}
+
+pub async fn dog_versions<R>(service: Data<Service<R>>, id: Path<(String,)>) -> Result<Json<Vec<DogVersion>>, Error>
+where
+    R: Repository,
+{
+    service.dog_versions(&id.0).await.map(Json).map_err(http_error)
+}
+
+pub async fn dog_version<R>(service: Data<Service<R>>, path: Path<(String, u64)>) -> Result<Json<DogVersion>, Error>
+where
+    R: Repository,
+{
+    service.dog_version(&path.0, path.1).await.map(Json).map_err(http_error)
+}
+
+pub async fn revert_dog<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    AcceptLanguage(locale): AcceptLanguage,
+    IfMatch(version): IfMatch,
+    path: Path<(String, u64)>,
+) -> Result<HttpResponse, Error>
+where
+    R: Repository,
+{
+    let mut dog = service.revert_dog(&actor, &path.0, path.1, version).await.map_err(http_error)?;
+    dog.localize(locale);
+    Ok(with_localized_etag(dog.version, locale, &dog))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncDogsReq {
+    token: Option<String>,
+    limit: Option<usize>,
+}
+
+pub async fn sync_dogs<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(uid): HeaderUserID,
+    AcceptLanguage(locale): AcceptLanguage,
+    Query(query): Query<SyncDogsReq>,
+) -> Result<Json<DogSync>, Error>
+where
+    R: Repository,
+{
+    let limit = query.limit.unwrap_or(100).clamp(1, 500);
+    let mut sync = service.sync_dogs(&uid, query.token.as_deref(), limit).await.map_err(http_error)?;
+    sync.dogs.localize(locale);
+    Ok(Json(sync))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct IsOwnerOfTheDogReq {
     id: String,
@@ -66,7 +321,7 @@ pub async fn is_owner_of_the_dog<R>(service: Data<Service<R>>, Query(query): Que
 where
     R: Repository,
 {
-    let is_owner = service.is_owner_of_the_dog(&query.owner_id, &query.id).await.map_err(ErrorInternalServerError)?;
+    let is_owner = service.is_owner_of_the_dog(&query.owner_id, &query.id).await.map_err(http_error)?;
     Ok(Json(IsOwnerOfTheDogResp { is_owner }))
 }
 
@@ -80,10 +335,19 @@ pub struct UpdateDogPortraitResp {
     has_updated: bool,
 }
 
-pub async fn update_dog_portrait<R>(service: Data<Service<R>>, dog_id: Path<(String,)>, Json(query): Json<UpdateDogPortraitReq>) -> Result<Json<UpdateDogPortraitResp>, Error>
+pub async fn update_dog_portrait<R>(
+    service: Data<Service<R>>,
+    HeaderUserID(actor): HeaderUserID,
+    IfMatch(version): IfMatch,
+    dog_id: Path<(String,)>,
+    Json(query): Json<UpdateDogPortraitReq>,
+) -> Result<Json<UpdateDogPortraitResp>, Error>
 where
     R: Repository,
 {
-    let has_updated = service.update_dog_portrait(&dog_id.as_ref().0, &query.portrait_id).await.map_err(ErrorInternalServerError)?;
+    let has_updated = service
+        .update_dog_portrait(&actor, &dog_id.as_ref().0, &query.portrait_id, version)
+        .await
+        .map_err(http_error)?;
     Ok(Json(UpdateDogPortraitResp { has_updated }))
 }
@@ -1,3 +1,6 @@
+pub mod audit;
 pub mod breed;
+pub mod breed_group;
 pub mod common;
 pub mod dog;
+pub mod webhook;
@@ -0,0 +1,57 @@
+use crate::{
+    core::{
+        repository::Repository,
+        service::Service,
+        webhook::{Webhook, WebhookCreate, WebhookDelivery, WebhookDeliveryQuery},
+    },
+    handlers::common::http_error,
+};
+use actix_web::{
+    web::{Data, Json, Path},
+    Error, HttpResponse,
+};
+use nb_serde_query::actix_web::Query;
+
+pub async fn create_webhook<R>(service: Data<Service<R>>, Json(webhook): Json<WebhookCreate>) -> Result<Json<Webhook>, Error>
+where
+    R: Repository,
+{
+    service.create_webhook(&webhook).await.map(Json).map_err(http_error)
+}
+
+pub async fn webhooks<R>(service: Data<Service<R>>) -> Result<Json<Vec<Webhook>>, Error>
+where
+    R: Repository,
+{
+    service.webhooks().await.map(Json).map_err(http_error)
+}
+
+pub async fn webhook<R>(service: Data<Service<R>>, id: Path<(String,)>) -> Result<Json<Webhook>, Error>
+where
+    R: Repository,
+{
+    service.webhook(&id.0).await.map(Json).map_err(http_error)
+}
+
+pub async fn delete_webhook<R>(service: Data<Service<R>>, id: Path<(String,)>) -> Result<HttpResponse, Error>
+where
+    R: Repository,
+{
+    service.delete_webhook(&id.0).await.map_err(http_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// 死信: ?status=dead, 也可以按订阅过滤: ?webhook_id=xxx
+pub async fn webhook_deliveries<R>(service: Data<Service<R>>, Query(query): Query<WebhookDeliveryQuery>) -> Result<Json<Vec<WebhookDelivery>>, Error>
+where
+    R: Repository,
+{
+    service.webhook_deliveries(&query).await.map(Json).map_err(http_error)
+}
+
+pub async fn redeliver_webhook<R>(service: Data<Service<R>>, id: Path<(String,)>) -> Result<Json<WebhookDelivery>, Error>
+where
+    R: Repository,
+{
+    service.redeliver_webhook(&id.0).await.map(Json).map_err(http_error)
+}
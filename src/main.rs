@@ -3,19 +3,33 @@ pub mod handlers;
 pub mod middlewares;
 pub mod repositories;
 
-use core::service::Service;
+use core::{
+    age::LifeStageThresholds,
+    audit::SYSTEM_ACTOR,
+    breed_import::{self, ImportFormat},
+    events::{Dispatcher, EventSink, InProcessSink},
+    locale::Locale,
+    repository::Repository,
+    service::Service,
+    webhook::WebhookSink,
+};
 
 use actix_web::{
     middleware::Logger,
-    web::{get, post, put, resource, scope, Data},
+    web::{delete, get, patch, post, put, resource, scope, Data, JsonConfig, PayloadConfig},
     App, HttpServer,
 };
+use chrono::Duration;
 use env_logger::Env;
-use middlewares::response_encoding::ResponseEncoding;
+use futures::StreamExt;
+use middlewares::{idempotency::IdempotencyKeys, response_encoding::ResponseEncoding};
 use mongodb::Client;
 use nb_from_env::{FromEnv, FromEnvDerive};
 use repositories::mongodb::MongoDB;
 
+// 品种导入的请求体上限, 幂等中间件需要读取同样大小的请求体
+const BREED_IMPORT_PAYLOAD_LIMIT: usize = 16 * 1024 * 1024;
+
 #[derive(FromEnvDerive)]
 pub struct Config {
     listen_address: String,
@@ -23,32 +37,236 @@ pub struct Config {
     log_level: String,
     #[env_default("%t %r %s %D")]
     log_format: String,
-    mongodb_uri: String,
+    #[env_default("zh")]
+    default_locale: String,
+    #[env_default("Small=10/120,Medium=12/96,Large=15/84,Giant=18/72")]
+    life_stage_thresholds: String,
+    #[env_default("30")]
+    dog_restore_days: String, // 删除后可恢复的天数
+    #[env_default("90")]
+    dog_retention_days: String, // 删除后超过该天数彻底删除
+    #[env_default("")]
+    event_sinks: String, // 领域事件除进程内外还投递到哪里, 如"stdout,file:/var/log/dog-events.jsonl"
+    #[env_default("8")]
+    webhook_max_attempts: String, // webhook投递失败超过该次数后进入死信
+    #[env_default("10")]
+    webhook_timeout_secs: String,
+    #[env_default("24")]
+    idempotency_window_hours: String, // Idempotency-Key的响应保留的小时数
+    #[env_default("300")]
+    idempotency_lease_secs: String, // 第一次请求中断后, 超过该秒数才能用同一个Idempotency-Key重试
+    #[env_default("100")]
+    dog_batch_max_size: String, // 批量创建和修改时每次最多的狗狗数量
+    mongodb_uri: String, // 需要副本集或分片集群以支持事务, 如mongodb://localhost:27021/?replicaSet=rs0&directConnection=true
     mongodb_database_name: String,
 }
 
+// little-walk-dog import-breeds <file.csv|file.json> [--dry-run]
+async fn import_breeds<R>(service: &Service<R>, args: &[String]) -> std::io::Result<()>
+where
+    R: Repository,
+{
+    let path = args
+        .iter()
+        .find(|a| !a.starts_with("--"))
+        .ok_or(std::io::Error::other("usage: import-breeds <file> [--dry-run]"))?;
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let format = if path.to_lowercase().ends_with(".csv") {
+        ImportFormat::Csv
+    } else {
+        ImportFormat::Json
+    };
+    let rows = breed_import::parse(&std::fs::read(path)?, format).map_err(|e| std::io::Error::other(e.to_string()))?;
+    let report = service
+        .import_breeds(SYSTEM_ACTOR, rows, dry_run)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
     let config = Config::from_env();
     env_logger::init_from_env(Env::default().default_filter_or(config.log_level));
     let client = Client::with_uri_str(config.mongodb_uri).await.expect("failed to connect to mongodb");
-    let service = Data::new(Service::new(MongoDB::new(client.database(&config.mongodb_database_name))));
+    let default_locale = Data::new(config.default_locale.parse::<Locale>().expect("invalid default locale"));
+    let repository = MongoDB::new(client.database(&config.mongodb_database_name));
+    let watcher = MongoDB::new(client.database(&config.mongodb_database_name));
+    repository.check_transactions().await.expect("unsupported mongodb deployment");
+    if let Err(e) = repository.init().await {
+        eprintln!("failed to init mongodb: {}", e);
+    }
+    let life_stages = config
+        .life_stage_thresholds
+        .parse::<LifeStageThresholds>()
+        .expect("invalid life stage thresholds");
+    let restore_days = config.dog_restore_days.parse::<i64>().expect("invalid dog restore days");
+    let webhook_max_attempts = config.webhook_max_attempts.parse::<u32>().expect("invalid webhook max attempts");
+    let webhook_timeout = config.webhook_timeout_secs.parse::<u64>().expect("invalid webhook timeout");
+    let idempotency_hours = config.idempotency_window_hours.parse::<i64>().expect("invalid idempotency window hours");
+    let idempotency_lease = config.idempotency_lease_secs.parse::<i64>().expect("invalid idempotency lease secs");
+    let batch_max_size = config.dog_batch_max_size.parse::<usize>().expect("invalid dog batch max size");
+    let retention_days = config.dog_retention_days.parse::<i64>().expect("invalid dog retention days");
+    let events = InProcessSink::new(1024);
+    let mut dispatcher = config.event_sinks.parse::<Dispatcher>().expect("invalid event sinks");
+    let service = Data::new(
+        Service::new(repository)
+            .with_life_stages(life_stages)
+            .with_restore_window(Duration::days(restore_days))
+            .with_webhook_max_attempts(webhook_max_attempts)
+            .with_webhook_timeout(Duration::seconds(webhook_timeout as i64))
+            .with_idempotency_window(Duration::hours(idempotency_hours))
+            .with_idempotency_lease(Duration::seconds(idempotency_lease))
+            .with_max_batch_size(batch_max_size),
+    );
+    let args = std::env::args().collect::<Vec<String>>();
+    if args.get(1).map(String::as_str) == Some("import-breeds") {
+        return import_breeds(&service, &args[2..]).await;
+    }
+    // 每小时清理一次超过保留期的已删除狗狗
+    let purger = service.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            if let Err(e) = purger.purge_dogs(Duration::days(retention_days)).await {
+                eprintln!("failed to purge dogs: {}", e);
+            }
+        }
+    });
+    // 进程内的事件来自change stream, 以便多个实例都能收到, 监听失败时退化为由本实例的Dispatcher投递
+    match watcher.watch_events().await {
+        Ok(mut changes) => {
+            let feed = events.clone();
+            actix_web::rt::spawn(async move {
+                while let Some(event) = changes.next().await {
+                    match event {
+                        Ok(event) => {
+                            let _ = feed.publish(std::slice::from_ref(&event)).await;
+                        }
+                        Err(e) => eprintln!("failed to watch events: {}", e),
+                    }
+                }
+            });
+        }
+        Err(e) => {
+            eprintln!("change streams unavailable, using in-process events: {}", e);
+            dispatcher = dispatcher.with_sink(events.clone());
+        }
+    }
+    // 每秒投递一次发件箱中的事件, 有积压时连续投递
+    let dispatcher = dispatcher.with_sink(WebhookSink::new(service.clone().into_inner()));
+    let relay = service.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            loop {
+                match relay.dispatch_events(&dispatcher, 100).await {
+                    Ok(100) => continue,
+                    Ok(_) => break,
+                    Err(e) => {
+                        eprintln!("failed to dispatch events: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    // 每5秒投递一次到期的webhook
+    let webhook_client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(webhook_timeout))
+        .build()
+        .expect("failed to build webhook client");
+    let deliverer = service.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            if let Err(e) = deliverer.deliver_webhooks(&webhook_client, 100).await {
+                eprintln!("failed to deliver webhooks: {}", e);
+            }
+        }
+    });
     HttpServer::new(move || {
-        App::new().app_data(service.clone()).wrap(ResponseEncoding).wrap(Logger::new(config.log_format.as_str())).service(
-            scope("apis")
-                .service(resource("breeds").post(handlers::breed::create_breed::<MongoDB>).get(handlers::breed::breeds::<MongoDB>))
-                .service(
-                    scope("dogs")
-                        .route("", post().to(handlers::dog::create_dog::<MongoDB>))
-                        .route("", get().to(handlers::dog::dogs::<MongoDB>))
-                        .route("", put().to(handlers::dog::update_dog::<MongoDB>))
-                        .route("mine", get().to(handlers::dog::my_dogs::<MongoDB>))
-                        .route("exists", get().to(handlers::dog::is_owner_of_the_dog::<MongoDB>))
-                        .route("{id}/portrait", put().to(handlers::dog::update_dog_portrait::<MongoDB>))
-                        .route("{id}", put().to(handlers::dog::update_dog::<MongoDB>)),
-                ),
-        )
+        App::new()
+            .app_data(service.clone())
+            .app_data(default_locale.clone())
+            .app_data(Data::new(events.clone()))
+            .app_data(JsonConfig::default().error_handler(handlers::common::json_error_handler))
+            .wrap(ResponseEncoding)
+            .wrap(Logger::new(config.log_format.as_str()))
+            .service(
+                scope("apis")
+                    .service(resource("breeds/search").get(handlers::breed::search_breeds::<MongoDB>))
+                    .service(
+                        resource("breeds")
+                            .post(handlers::breed::create_breed::<MongoDB>)
+                            .get(handlers::breed::breeds::<MongoDB>),
+                    )
+                    .service(
+                        resource("breeds/{id}")
+                            .get(handlers::breed::breed::<MongoDB>)
+                            .put(handlers::breed::update_breed::<MongoDB>),
+                    )
+                    .service(
+                        resource("admin/breeds/import")
+                            .app_data(PayloadConfig::new(BREED_IMPORT_PAYLOAD_LIMIT))
+                            .post(handlers::breed::import_breeds::<MongoDB>),
+                    )
+                    .service(resource("admin/breeds/{id}/merge").post(handlers::breed::merge_breed::<MongoDB>))
+                    .service(resource("admin/audit").get(handlers::audit::audit_log::<MongoDB>))
+                    .service(resource("admin/webhooks/deliveries").get(handlers::webhook::webhook_deliveries::<MongoDB>))
+                    .service(resource("admin/webhooks/deliveries/{id}/redeliver").post(handlers::webhook::redeliver_webhook::<MongoDB>))
+                    .service(
+                        resource("admin/webhooks")
+                            .post(handlers::webhook::create_webhook::<MongoDB>)
+                            .get(handlers::webhook::webhooks::<MongoDB>),
+                    )
+                    .service(
+                        resource("admin/webhooks/{id}")
+                            .get(handlers::webhook::webhook::<MongoDB>)
+                            .delete(handlers::webhook::delete_webhook::<MongoDB>),
+                    )
+                    .service(
+                        resource("breed-groups")
+                            .post(handlers::breed_group::create_breed_group::<MongoDB>)
+                            .get(handlers::breed_group::breed_groups::<MongoDB>),
+                    )
+                    .service(resource("breed-groups/{id}/breeds").get(handlers::breed_group::group_breeds::<MongoDB>))
+                    .service(
+                        scope("dogs")
+                            .route(
+                                "",
+                                post().to(handlers::dog::create_dog::<MongoDB>).wrap(IdempotencyKeys::<MongoDB>::new()),
+                            )
+                            .route("", get().to(handlers::dog::dogs::<MongoDB>))
+                            .route("mine", get().to(handlers::dog::my_dogs::<MongoDB>))
+                            .route("mine/events", get().to(handlers::dog::dog_events))
+                            .route(
+                                "batch",
+                                post().to(handlers::dog::create_dogs::<MongoDB>).wrap(IdempotencyKeys::<MongoDB>::new()),
+                            )
+                            .route("batch", patch().to(handlers::dog::update_dogs::<MongoDB>))
+                            .route("sync", get().to(handlers::dog::sync_dogs::<MongoDB>))
+                            .route("exists", get().to(handlers::dog::is_owner_of_the_dog::<MongoDB>))
+                            .route("{id}/portrait", put().to(handlers::dog::update_dog_portrait::<MongoDB>))
+                            .route("{id}/restore", post().to(handlers::dog::restore_dog::<MongoDB>))
+                            .route(
+                                "{id}/transfer",
+                                post().to(handlers::dog::transfer_dog::<MongoDB>).wrap(IdempotencyKeys::<MongoDB>::new()),
+                            )
+                            .route("{id}/versions", get().to(handlers::dog::dog_versions::<MongoDB>))
+                            .route("{id}/versions/{version}", get().to(handlers::dog::dog_version::<MongoDB>))
+                            .route("{id}/versions/{version}/revert", post().to(handlers::dog::revert_dog::<MongoDB>))
+                            .route("{id}", get().to(handlers::dog::dog::<MongoDB>))
+                            .route("{id}", put().to(handlers::dog::replace_dog::<MongoDB>))
+                            .route("{id}", patch().to(handlers::dog::patch_dog::<MongoDB>))
+                            .route("{id}", delete().to(handlers::dog::delete_dog::<MongoDB>)),
+                    ),
+            )
     })
     .bind(config.listen_address)?
     .run()
@@ -0,0 +1,187 @@
+use std::future::{ready, Ready};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::body;
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::PayloadError;
+use actix_web::http::{header, StatusCode};
+use actix_web::web::{Bytes, BytesMut, Data};
+use actix_web::{HttpMessage, HttpResponse};
+use futures::stream::{self, Stream, StreamExt};
+
+use crate::core::{
+    self,
+    idempotency::{self, Idempotency, StoredResponse},
+    repository::Repository,
+};
+use crate::handlers::common::http_error;
+
+// 重放第一次响应时需要保留的响应头
+const REPLAYED_HEADERS: [header::HeaderName; 3] = [header::CONTENT_TYPE, header::ETAG, header::LOCATION];
+
+// 带Idempotency-Key的请求: 按(X-User-ID, key)保存第一次成功的响应, 重试时原样返回并附带Idempotent-Replayed: true
+// 同一个key用于不同的请求体时返回409, 失败的响应(4xx, 5xx)不保存, 客户端修正后可以用同一个key重试
+// 只挂在创建和转让的路由上, 中间件先于处理函数读取请求体, 上限应与该路由的JsonConfig一致
+pub struct IdempotencyKeys<R> {
+    payload_limit: usize,
+    repository: PhantomData<R>,
+}
+
+impl<R> IdempotencyKeys<R> {
+    pub fn new() -> Self {
+        // 与JsonConfig默认的上限一致
+        Self {
+            payload_limit: 2 * 1024 * 1024,
+            repository: PhantomData,
+        }
+    }
+
+    pub fn with_payload_limit(self, payload_limit: usize) -> Self {
+        Self { payload_limit, ..self }
+    }
+}
+
+impl<R> Default for IdempotencyKeys<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, R> Transform<S, ServiceRequest> for IdempotencyKeys<R>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    R: Repository + 'static,
+{
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Transform = IdempotencyKeysService<S, R>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IdempotencyKeysService {
+            next: Rc::new(service),
+            payload_limit: self.payload_limit,
+            repository: PhantomData,
+        }))
+    }
+}
+
+pub struct IdempotencyKeysService<S, R> {
+    next: Rc<S>,
+    payload_limit: usize,
+    repository: PhantomData<R>,
+}
+
+// 超过上限时返回413
+async fn read_body(req: &mut ServiceRequest, limit: usize) -> Result<Bytes, PayloadError> {
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            return Err(PayloadError::Overflow);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+fn payload(body: Bytes) -> Payload {
+    let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> = Box::pin(stream::once(async move { Ok(body) }));
+    Payload::from(stream)
+}
+
+fn replay(stored: StoredResponse) -> HttpResponse {
+    let mut res = HttpResponse::build(StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK));
+    for (name, value) in stored.headers {
+        res.insert_header((name, value));
+    }
+    res.insert_header(("Idempotent-Replayed", "true"));
+    res.body(stored.body)
+}
+
+impl<S, R> Service<ServiceRequest> for IdempotencyKeysService<S, R>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    R: Repository + 'static,
+{
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, ctx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        self.next.poll_ready(ctx)
+    }
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let next = self.next.clone();
+        let payload_limit = self.payload_limit;
+        let key = req.headers().get("Idempotency-Key").and_then(|hv| hv.to_str().ok()).map(str::to_owned);
+        // 没有X-User-ID的请求由处理函数拒绝, 不占用key
+        let user_id = req.headers().get("X-User-ID").and_then(|hv| hv.to_str().ok()).map(str::to_owned);
+        let service = req.app_data::<Data<core::service::Service<R>>>().cloned();
+        let (Some(key), Some(user_id), Some(service)) = (key, user_id, service) else {
+            return Box::pin(next.call(req));
+        };
+        Box::pin(async move {
+            let body = read_body(&mut req, payload_limit).await?;
+            let fingerprint = idempotency::fingerprint(req.method().as_str(), &req.uri().to_string(), &body);
+            req.set_payload(payload(body));
+            match service.begin_idempotent(&user_id, &key, fingerprint).await {
+                Ok(Idempotency::New) => {}
+                Ok(Idempotency::Replay(stored)) => return Ok(req.into_response(replay(stored))),
+                Err(e) => return Ok(req.into_response(http_error(e).error_response())),
+            }
+            let res = match next.call(req).await {
+                Ok(res) => res,
+                Err(e) => {
+                    let _ = service.release_idempotent(&user_id, &key).await;
+                    return Err(e);
+                }
+            };
+            let (req, res) = res.into_parts();
+            let status = res.status();
+            let headers = REPLAYED_HEADERS
+                .iter()
+                .filter_map(|name| Some((name.to_string(), res.headers().get(name)?.to_str().ok()?.to_owned())))
+                .collect::<Vec<_>>();
+            let (res, body) = res.into_parts();
+            let body = body::to_bytes(body).await.unwrap_or_default();
+            let stored = StoredResponse {
+                status: status.as_u16(),
+                headers,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            };
+            let saved = if !status.is_success() {
+                service.release_idempotent(&user_id, &key).await
+            } else {
+                service.complete_idempotent(&user_id, &key, &stored).await
+            };
+            if let Err(e) = saved {
+                eprintln!("failed to save idempotent response: {}", e);
+            }
+            Ok(ServiceResponse::new(req, res.set_body(body).map_into_boxed_body()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn read_body_honors_payload_limit() {
+        let big = vec![b'a'; 1024 * 1024];
+        let mut req = TestRequest::post().set_payload(big.clone()).to_srv_request();
+        assert_eq!(read_body(&mut req, 16 * 1024 * 1024).await.unwrap().len(), big.len());
+        let mut req = TestRequest::post().set_payload(big).to_srv_request();
+        assert!(matches!(read_body(&mut req, 256 * 1024).await, Err(PayloadError::Overflow)));
+    }
+}
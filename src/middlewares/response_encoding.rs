@@ -46,6 +46,14 @@ where
         let future = self.next.call(req);
         Box::pin(async move {
             let mut res = future.await?;
+            // Server-Sent Events必须保留text/event-stream
+            if res
+                .headers()
+                .get("Content-Type")
+                .is_some_and(|ct| ct.as_bytes().starts_with(b"text/event-stream"))
+            {
+                return Ok(res);
+            }
             res.headers_mut()
                 .insert(HeaderName::from_str("Content-Type").unwrap(), HeaderValue::from_str("application/json; charset=utf-8").unwrap());
             Ok(res)
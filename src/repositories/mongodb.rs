@@ -1,29 +1,50 @@
-use std::ops::Deref;
+use std::{collections::HashMap, ops::Deref};
 
 use mongodb::{
-    bson::{doc, from_document, oid::ObjectId, to_document, Bson, Document},
-    options::FindOneOptions,
-    Database,
+    bson::{doc, from_bson, from_document, oid::ObjectId, to_bson, to_document, Bson, Document},
+    error::{ErrorKind, WriteFailure, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+    options::{FindOneAndDeleteOptions, FindOneAndUpdateOptions, FindOneOptions, IndexOptions, ReturnDocument, UpdateOptions},
+    ClientSession, Database, IndexModel,
 };
+use serde_json::json;
 
 use crate::core::{
-    entities::{Breed, Dog},
+    audit::{self, AuditAction, AuditEntity, AuditEntry, AuditQuery, FieldChange},
+    entities::{Birthday, Breed, BreedGroup, Dog, DogTombstone},
     error::Error,
-    repository::{BreedCreate, BreedQuery, DogCreate, DogQuery, DogUpdate, Repository},
+    events::{DomainEvent, EventType},
+    idempotency::{IdempotencyRecord, StoredResponse},
+    repository::{BreedCreate, BreedGroupCreate, BreedQuery, BreedUpdate, DogBatchUpdate, DogCreate, DogQuery, DogUpdate, DogVersion, Repository},
+    search,
+    webhook::{Webhook, WebhookCreate, WebhookDelivery, WebhookDeliveryQuery},
 };
 
 use mongodb::options::FindOptions;
 
-use futures::TryStreamExt;
+use futures::{Stream, StreamExt, TryStreamExt};
 
-use chrono::{Local, Utc};
+use chrono::{DateTime, Utc};
+
+// 额外保存生日可能范围的起止日期, 用于按生日过滤
+impl From<&Birthday> for Bson {
+    fn from(birthday: &Birthday) -> Self {
+        Bson::Document(doc! {
+            "date": birthday.date.to_string(),
+            "precision": to_bson(&birthday.precision).unwrap_or_default(),
+            "earliest": birthday.earliest().to_string(),
+            "latest": birthday.latest().to_string(),
+        })
+    }
+}
 
 impl TryFrom<&DogCreate> for Document {
     type Error = Error;
     fn try_from(dog: &DogCreate) -> Result<Self, Self::Error> {
         let mut d = to_document(&dog).map_err(|e| Error::new("failed to convert DogCreate to Document").with_cause(e))?;
+        d.insert("birthday", &dog.birthday);
         d.insert("created_at", Utc::now());
         d.insert("updated_at", Utc::now());
+        d.insert("version", 1_i64);
         Ok(d)
     }
 }
@@ -35,10 +56,89 @@ impl Dog {
             "name": 1,
             "gender": 1,
             "breed": 1,
+            "breed_components": 1,
+            "unknown_mix": 1,
             "birthday": 1,
+            "is_sterilized": 1,
+            "introduction": 1,
             "owner_id": 1,
             "tags": 1,
             "portrait_id": 1,
+            "version": 1,
+            "seq": 1,
+            "created_at": timestamp("created_at"),
+            "updated_at": timestamp("updated_at"),
+        }
+    }
+}
+
+impl DogVersion {
+    pub fn projection() -> Document {
+        doc! {
+            "_id": 0,
+            "dog_id": 1,
+            "version": 1,
+            "dog": 1,
+            "actor": 1,
+            "at": timestamp("at"),
+        }
+    }
+}
+
+impl Webhook {
+    pub fn projection() -> Document {
+        doc! {
+            "id": { "$toString": "$_id" },
+            "url": 1,
+            "event_types": 1,
+            "secret": 1,
+            "dog_ids": 1,
+            "created_at": timestamp("created_at"),
+        }
+    }
+}
+
+impl WebhookDelivery {
+    pub fn projection() -> Document {
+        doc! {
+            "id": { "$toString": "$_id" },
+            "webhook_id": 1,
+            "event": 1,
+            "status": 1,
+            "attempts": 1,
+            "next_attempt_at": timestamp("next_attempt_at"),
+            "last_error": 1,
+            "created_at": timestamp("created_at"),
+            "delivered_at": timestamp("delivered_at"),
+        }
+    }
+}
+
+impl Breed {
+    pub fn projection() -> Document {
+        doc! {
+            "id": { "$toString": "$_id" },
+            "category": 1,
+            "name": 1,
+            "names": 1,
+            "aliases": 1,
+            "attributes": 1,
+            "group_id": 1,
+            "parent_id": 1,
+            "version": 1,
+            "created_at": timestamp("created_at"),
+            "updated_at": timestamp("updated_at"),
+        }
+    }
+}
+
+impl BreedGroup {
+    pub fn projection() -> Document {
+        doc! {
+            "id": { "$toString": "$_id" },
+            "name": 1,
+            "names": 1,
+            "fci_number": 1,
         }
     }
 }
@@ -52,133 +152,1352 @@ impl From<Dog> for Bson {
     }
 }
 
+fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 pub struct MongoDB {
     db: Database,
 }
 
+// 时间统一以UTC的BSON日期保存, 读取时转换为RFC 3339字符串
+fn timestamp(field: &str) -> Document {
+    doc! { "$dateToString": { "date": format!("${}", field), "format": "%Y-%m-%dT%H:%M:%S.%LZ" } }
+}
+
+// 带版本号的写操作只匹配版本号一致的记录
+fn versioned(id: ObjectId, version: Option<u64>) -> Document {
+    let mut filter = doc! { "_id": id };
+    if let Some(version) = version {
+        filter.insert("version", version as i64);
+    }
+    filter
+}
+
+// 未被删除的狗狗, 已删除的狗狗不能修改
+fn live_dog(id: ObjectId, version: Option<u64>) -> Document {
+    let mut filter = versioned(id, version);
+    filter.insert("deleted_at", Bson::Null);
+    filter
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(e.kind.as_ref(), ErrorKind::Write(WriteFailure::WriteError(we)) if we.code == 11000)
+}
+
+fn breed_conflict(existing_id: Option<String>) -> Error {
+    Error::conflict("breed already exists").with_details(json!({ "existing_id": existing_id }))
+}
+
+// 事务中的操作与其它事务修改同一条记录时, 服务端中止整个事务, 可以安全地重试
+const MAX_TRANSACTION_RETRIES: u64 = 5;
+const WRITE_CONFLICT: &str = "concurrent modification, please retry";
+
+// 预留的变更序号在此之后视为已放弃, 需要长于事务的最长执行时间(默认60秒)
+const SEQ_RESERVATION_LEASE_SECS: i64 = 300;
+
+fn write_error(message: &str, e: mongodb::error::Error) -> Error {
+    if e.contains_label(TRANSIENT_TRANSACTION_ERROR) {
+        return Error::conflict(WRITE_CONFLICT).with_cause(e);
+    }
+    Error::new(message).with_cause(e)
+}
+
+fn is_write_conflict(e: &Error) -> bool {
+    e.kind() == crate::core::error::ErrorKind::Conflict && e.to_string().starts_with(WRITE_CONFLICT)
+}
+
+// 一个写事务, 领域事件在提交时写入发件箱, 审计日志在事务中直接写入
+struct Transaction {
+    session: ClientSession,
+    actor: String,
+    reservations: Vec<ObjectId>, // 本事务预留变更序号的记录, 与修改一起删除
+    seqs: std::ops::Range<i64>,  // 已预留但还没有使用的序号
+    events: Vec<DomainEvent>,
+    rollback: bool,       // 为true时不提交
+    commit_unknown: bool, // 提交结果未知时不能删除预留记录, 只能等待过期
+}
+
+impl Transaction {
+    fn new(session: ClientSession, actor: &str) -> Self {
+        Self {
+            session,
+            actor: actor.to_owned(),
+            reservations: vec![],
+            seqs: 0..0,
+            events: vec![],
+            rollback: false,
+            commit_unknown: false,
+        }
+    }
+}
+
+// 更新中修改的业务字段, 不包括更新时间等内部字段
+fn changed_fields(update: &Document) -> Vec<String> {
+    ["$set", "$unset"]
+        .iter()
+        .filter_map(|op| update.get_document(op).ok())
+        .flat_map(|fields| fields.keys())
+        .filter(|f| !matches!(f.as_str(), "updated_at" | "seq" | "name_key" | "search_keys"))
+        .cloned()
+        .collect()
+}
+
+// 无法解析的品种id与不存在的品种一样返回404
+fn breed_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| Error::not_found("breed not exists").with_cause(id.to_owned()))
+}
+
+// 未删除的狗狗中按id, 主人, 生日和时间过滤, 品种分组需要先查出品种id, 由query_dogs处理
+fn dog_filter(query: &DogQuery) -> Result<Document, Error> {
+    let mut q = doc! { "deleted_at": Bson::Null };
+    let mut ids = doc! {};
+    if let Some(id) = &query.id {
+        ids.insert(
+            "$eq",
+            ObjectId::parse_str(id).map_err(|_| Error::not_found("dog not exists").with_cause(id.to_owned()))?,
+        );
+    }
+    if let Some(id_in) = &query.id_in {
+        let id_in = id_in
+            .deref()
+            .iter()
+            .map(|id| ObjectId::parse_str(id).map_err(|e| Error::invalid_input("invalid dog id").with_cause(e)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        ids.insert("$in", id_in);
+    }
+    if !ids.is_empty() {
+        q.insert("_id", ids);
+    }
+    if let Some(owner_id) = &query.owner_id {
+        q.insert("owner_id", owner_id);
+    }
+    if let Some(born_after) = &query.born_after {
+        q.insert("birthday.latest", doc! { "$gte": born_after.to_string() });
+    }
+    if let Some(born_before) = &query.born_before {
+        q.insert("birthday.earliest", doc! { "$lte": born_before.to_string() });
+    }
+    if let Some(created_after) = query.created_after {
+        q.insert("created_at", doc! { "$gte": created_after });
+    }
+    if let Some(updated_after) = query.updated_after {
+        q.insert("updated_at", doc! { "$gte": updated_after });
+    }
+    Ok(q)
+}
+
+// 部分更新的$set/$unset, 没有要修改的字段时返回None
+fn dog_update(dog: &DogUpdate) -> Result<Option<Document>, Error> {
+    let mut set = doc! {};
+    let mut unset = doc! {};
+    if let Some(name) = &dog.name {
+        set.insert("name", name);
+    }
+    if let Some(gender) = &dog.gender {
+        set.insert("gender", to_bson(gender).map_err(|e| Error::new("failed to update dog").with_cause(e))?);
+    }
+    if let Some(breed) = &dog.breed {
+        set.insert("breed", to_document(breed).map_err(|e| Error::new("failed to update dog").with_cause(e))?);
+    }
+    if let Some(breed_components) = &dog.breed_components {
+        set.insert(
+            "breed_components",
+            to_bson(breed_components).map_err(|e| Error::new("failed to update dog").with_cause(e))?,
+        );
+    }
+    if let Some(unknown_mix) = &dog.unknown_mix {
+        set.insert("unknown_mix", unknown_mix);
+    }
+    if let Some(birthday) = &dog.birthday {
+        set.insert("birthday", birthday);
+    }
+    if let Some(is_sterilized) = &dog.is_sterilized {
+        set.insert("is_sterilized", is_sterilized);
+    }
+    match &dog.introduction {
+        Some(Some(introduction)) => {
+            set.insert("introduction", introduction);
+        }
+        Some(None) => {
+            unset.insert("introduction", "");
+        }
+        None => {}
+    }
+    if let Some(owner_id) = &dog.owner_id {
+        set.insert("owner_id", owner_id);
+    }
+    if let Some(tags) = &dog.tags {
+        set.insert("tags", tags);
+    }
+    match &dog.portrait_id {
+        Some(Some(portrait_id)) => {
+            set.insert("portrait_id", portrait_id);
+        }
+        Some(None) => {
+            unset.insert("portrait_id", "");
+        }
+        None => {}
+    }
+    if set.is_empty() && unset.is_empty() {
+        return Ok(None);
+    }
+    set.insert("updated_at", Utc::now());
+    let mut update = doc! { "$set": set, "$inc": { "version": 1 } };
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+    Ok(Some(update))
+}
+
 impl MongoDB {
     pub fn new(db: Database) -> Self {
         Self { db }
     }
+
+    // 写操作依赖多文档事务, 单机部署的MongoDB不支持, 启动时检查以免运行后每次写入都失败
+    // 开发环境可以用单节点的副本集: mongod --replSet rs0, 再执行rs.initiate()
+    pub async fn check_transactions(&self) -> Result<(), Error> {
+        let hello = self
+            .db
+            .run_command(doc! { "hello": 1 }, None)
+            .await
+            .map_err(|e| Error::new("failed to check mongodb deployment").with_cause(e))?;
+        // 副本集的成员有setName, 分片集群的mongos返回msg: isdbgrid
+        if hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid") {
+            return Ok(());
+        }
+        Err(Error::new(
+            "mongodb must be deployed as a replica set or sharded cluster to support transactions",
+        ))
+    }
+
+    // 补全旧数据的name_key和search_keys并创建(category, name_key)唯一索引, 已有重复品种时需要先合并
+    pub async fn init(&self) -> Result<(), Error> {
+        let breeds = self.db.collection::<Document>("breeds");
+        let mut cursor = breeds
+            .find(
+                doc! { "$or": [{ "name_key": { "$exists": false } }, { "search_keys": { "$exists": false } }] },
+                None,
+            )
+            .await
+            .map_err(|e| Error::new("failed to init breeds").with_cause(e))?;
+        while let Some(d) = cursor.try_next().await.map_err(|e| Error::new("failed to init breeds").with_cause(e))? {
+            let (Ok(id), Ok(name)) = (d.get_object_id("_id"), d.get_str("name")) else {
+                continue;
+            };
+            let aliases = d
+                .get_array("aliases")
+                .into_iter()
+                .flatten()
+                .chain(d.get_document("names").into_iter().flat_map(|names| names.values()))
+                .filter_map(|a| a.as_str().map(str::to_owned))
+                .collect::<Vec<String>>();
+            let set = doc! { "name_key": search::normalize(name), "search_keys": search::search_keys(name, &aliases) };
+            breeds
+                .update_one(doc! { "_id": id }, doc! { "$set": set }, None)
+                .await
+                .map_err(|e| Error::new("failed to init breeds").with_cause(e))?;
+        }
+        for collection in ["breeds", "dogs"] {
+            let c = self.db.collection::<Document>(collection);
+            // 旧数据没有版本号
+            c.update_many(doc! { "version": { "$exists": false } }, doc! { "$set": { "version": 1_i64 } }, None)
+                .await
+                .map_err(|e| Error::new("failed to init versions").with_cause(e))?;
+            // 旧数据的时间保存为本地时区的RFC 3339字符串或者没有保存, 统一转换为BSON日期, 缺少的创建时间取自ObjectId
+            let backfills = [
+                (
+                    doc! { "created_at": { "$type": "string" } },
+                    doc! { "created_at": { "$toDate": "$created_at" } },
+                ),
+                (
+                    doc! { "updated_at": { "$type": "string" } },
+                    doc! { "updated_at": { "$toDate": "$updated_at" } },
+                ),
+                (doc! { "created_at": { "$exists": false } }, doc! { "created_at": { "$toDate": "$_id" } }),
+                (doc! { "updated_at": { "$exists": false } }, doc! { "updated_at": "$created_at" }),
+            ];
+            for (filter, set) in backfills {
+                c.update_many(filter, vec![doc! { "$set": set }], None)
+                    .await
+                    .map_err(|e| Error::new("failed to init timestamps").with_cause(e))?;
+            }
+        }
+        // 旧数据没有变更序号, 逐个分配
+        let dogs = self.db.collection::<Document>("dogs");
+        let mut cursor = dogs
+            .find(doc! { "seq": { "$exists": false } }, None)
+            .await
+            .map_err(|e| Error::new("failed to init dogs").with_cause(e))?;
+        while let Some(d) = cursor.try_next().await.map_err(|e| Error::new("failed to init dogs").with_cause(e))? {
+            let Ok(id) = d.get_object_id("_id") else {
+                continue;
+            };
+            let seq = self.allocate_seqs(1).await?;
+            dogs.update_one(doc! { "_id": id }, doc! { "$set": { "seq": seq } }, None)
+                .await
+                .map_err(|e| Error::new("failed to init dogs").with_cause(e))?;
+        }
+        // 旧数据的生日为时间字符串, 没有精度和可能范围, 转换后才能按生日过滤
+        let mut cursor = dogs
+            .find(doc! { "birthday.latest": { "$exists": false } }, None)
+            .await
+            .map_err(|e| Error::new("failed to init birthdays").with_cause(e))?;
+        while let Some(d) = cursor
+            .try_next()
+            .await
+            .map_err(|e| Error::new("failed to init birthdays").with_cause(e))?
+        {
+            let (Ok(id), Some(birthday)) = (d.get_object_id("_id"), d.get("birthday")) else {
+                continue;
+            };
+            let birthday = match birthday {
+                Bson::DateTime(dt) => Bson::String(dt.to_chrono().to_rfc3339()),
+                b => b.clone(),
+            };
+            let birthday = match from_bson::<Birthday>(birthday) {
+                Ok(birthday) => birthday,
+                Err(e) => {
+                    eprintln!("invalid birthday of dog {}: {}", id, e);
+                    continue;
+                }
+            };
+            dogs.update_one(doc! { "_id": id }, doc! { "$set": { "birthday": &birthday } }, None)
+                .await
+                .map_err(|e| Error::new("failed to init birthdays").with_cause(e))?;
+        }
+        self.db
+            .collection::<Document>("dog_versions")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "dog_id": 1, "version": -1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await
+            .map_err(|e| Error::new("failed to create dog version index").with_cause(e))?;
+        // 未投递的事件dispatched_at为null, 不会过期, 已投递的事件保留7天
+        self.db
+            .collection::<Document>("outbox")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "dispatched_at": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(std::time::Duration::from_secs(7 * 24 * 3600))
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await
+            .map_err(|e| Error::new("failed to create outbox index").with_cause(e))?;
+        // 旧的幂等记录没有锁定期限, 视为从创建时起已经过期
+        let idempotency_keys = self.db.collection::<Document>("idempotency_keys");
+        idempotency_keys
+            .update_many(
+                doc! { "locked_until": { "$exists": false } },
+                vec![doc! { "$set": { "locked_until": "$created_at" } }],
+                None,
+            )
+            .await
+            .map_err(|e| Error::new("failed to init idempotency keys").with_cause(e))?;
+        for (keys, options) in [
+            (doc! { "user_id": 1, "key": 1 }, IndexOptions::builder().unique(true).build()),
+            (
+                doc! { "expires_at": 1 },
+                IndexOptions::builder().expire_after(std::time::Duration::ZERO).build(),
+            ),
+        ] {
+            idempotency_keys
+                .create_index(IndexModel::builder().keys(keys).options(options).build(), None)
+                .await
+                .map_err(|e| Error::new("failed to create idempotency key index").with_cause(e))?;
+        }
+        let deliveries = self.db.collection::<Document>("webhook_deliveries");
+        for keys in [doc! { "status": 1, "next_attempt_at": 1 }, doc! { "webhook_id": 1, "created_at": -1 }] {
+            deliveries
+                .create_index(IndexModel::builder().keys(keys).build(), None)
+                .await
+                .map_err(|e| Error::new("failed to create webhook delivery index").with_cause(e))?;
+        }
+        let audit_log = self.db.collection::<Document>("audit_log");
+        for keys in [doc! { "entity": 1, "entity_id": 1, "at": -1 }, doc! { "actor": 1, "at": -1 }] {
+            audit_log
+                .create_index(IndexModel::builder().keys(keys).build(), None)
+                .await
+                .map_err(|e| Error::new("failed to create audit log index").with_cause(e))?;
+        }
+        // 放弃的预留记录过期后由TTL索引删除
+        let lease = std::time::Duration::from_secs(SEQ_RESERVATION_LEASE_SECS as u64);
+        self.db
+            .collection::<Document>("seq_reservations")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "reserved_at": 1 })
+                    .options(IndexOptions::builder().expire_after(lease).build())
+                    .build(),
+                None,
+            )
+            .await
+            .map_err(|e| Error::new("failed to create sequence reservation index").with_cause(e))?;
+        for collection in ["dogs", "dog_tombstones"] {
+            self.db
+                .collection::<Document>(collection)
+                .create_index(IndexModel::builder().keys(doc! { "owner_id": 1, "seq": 1 }).build(), None)
+                .await
+                .map_err(|e| Error::new("failed to create sync index").with_cause(e))?;
+        }
+        breeds
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "category": 1, "name_key": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await
+            .map_err(|e| Error::new("failed to create unique index on breed names").with_cause(e))?;
+        Ok(())
+    }
+
+    async fn find_breed_conflict(&self, category: &str, name_key: &str, exclude: Option<ObjectId>) -> Result<Option<String>, Error> {
+        let mut q = doc! { "category": category, "name_key": name_key };
+        if let Some(exclude) = exclude {
+            q.insert("_id", doc! { "$ne": exclude });
+        }
+        Ok(self
+            .db
+            .collection::<Document>("breeds")
+            .find_one(q, None)
+            .await
+            .map_err(|e| Error::new("failed to check breed name").with_cause(e))?
+            .and_then(|d| d.get_object_id("_id").ok())
+            .map(|id| id.to_string()))
+    }
+
+    // 全局递增的变更序号, 用于增量同步, 不在事务中分配, 以免所有写操作都在计数器上冲突
+    // 分配前先写入预留记录, 以当时的计数作为下限, 预留记录与修改在同一个事务中删除;
+    // 同步只返回不超过最小下限的变更(见seq_watermark), 未提交的修改不会被跳过, 客户端同步到某个序号后不会再出现更小的序号
+    async fn reserve_seqs(&self, tx: &mut Transaction, n: i64) -> Result<(), Error> {
+        let floor = self.current_seq().await?;
+        let res = self
+            .db
+            .collection::<Document>("seq_reservations")
+            .insert_one(doc! { "floor": floor, "reserved_at": Utc::now() }, None)
+            .await
+            .map_err(|e| Error::new("failed to reserve sequence").with_cause(e))?;
+        let id = res
+            .inserted_id
+            .as_object_id()
+            .ok_or(Error::new("failed to reserve sequence").with_cause("invalid inserted id"))?;
+        tx.reservations.push(id);
+        let last = self.allocate_seqs(n).await?;
+        tx.seqs = last - n + 1..last + 1;
+        Ok(())
+    }
+
+    async fn current_seq(&self) -> Result<i64, Error> {
+        Ok(self
+            .db
+            .collection::<Document>("counters")
+            .find_one(doc! { "_id": "dogs" }, None)
+            .await
+            .map_err(|e| Error::new("failed to read sequence").with_cause(e))?
+            .and_then(|d| d.get_i64("seq").ok())
+            .unwrap_or_default())
+    }
+
+    // 分配n个连续的序号, 返回最后一个
+    async fn allocate_seqs(&self, n: i64) -> Result<i64, Error> {
+        self.db
+            .collection::<Document>("counters")
+            .find_one_and_update(
+                doc! { "_id": "dogs" },
+                doc! { "$inc": { "seq": n } },
+                FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+            .map_err(|e| Error::new("failed to allocate sequence").with_cause(e))?
+            .and_then(|d| d.get_i64("seq").ok())
+            .ok_or(Error::new("failed to allocate sequence"))
+    }
+
+    async fn next_seq(&self, tx: &mut Transaction) -> Result<i64, Error> {
+        if tx.seqs.is_empty() {
+            self.reserve_seqs(tx, 1).await?;
+        }
+        tx.seqs.next().ok_or(Error::new("failed to allocate sequence"))
+    }
+
+    // 所有不超过该序号的修改都已提交: 先读计数再读预留记录, 之后预留的序号都大于读到的计数
+    async fn seq_watermark(&self) -> Result<i64, Error> {
+        let current = self.current_seq().await?;
+        let floor = self
+            .db
+            .collection::<Document>("seq_reservations")
+            .find_one(
+                doc! { "reserved_at": { "$gt": Utc::now() - chrono::Duration::seconds(SEQ_RESERVATION_LEASE_SECS) } },
+                FindOneOptions::builder().sort(doc! { "floor": 1 }).build(),
+            )
+            .await
+            .map_err(|e| Error::new("failed to read sequence reservations").with_cause(e))?
+            .and_then(|d| d.get_i64("floor").ok());
+        Ok(floor.map_or(current, |floor| floor.min(current)))
+    }
+
+    async fn session(&self) -> Result<ClientSession, Error> {
+        self.db
+            .collection::<Document>("outbox")
+            .client()
+            .start_session(None)
+            .await
+            .map_err(|e| Error::new("failed to start session").with_cause(e))
+    }
+
+    // 写操作和发件箱中的领域事件在同一个事务中提交, MongoDB需要以副本集方式部署(单节点的副本集即可)
+    async fn start_transaction(&self) -> Result<ClientSession, Error> {
+        let mut session = self.session().await?;
+        session
+            .start_transaction(None)
+            .await
+            .map_err(|e| Error::new("failed to start transaction").with_cause(e))?;
+        Ok(session)
+    }
+
+    // 在新的事务中执行f, 与其它事务写冲突时重新执行, 多次冲突后返回409
+    // 没有提交时立即删除预留的变更序号, 不必等到过期
+    async fn transaction<T>(&self, actor: &str, mut f: impl AsyncFnMut(&mut Transaction) -> Result<T, Error>) -> Result<T, Error> {
+        let mut attempts = 0;
+        loop {
+            let mut tx = Transaction::new(self.start_transaction().await?, actor);
+            let res = match f(&mut tx).await {
+                Ok(value) if !tx.rollback => self.commit(&mut tx).await.map(|_| value),
+                res => res,
+            };
+            if (res.is_err() || tx.rollback) && !tx.commit_unknown && !tx.reservations.is_empty() {
+                // 先回滚, 否则事务中对预留记录的修改会阻塞删除
+                let _ = tx.session.abort_transaction().await;
+                if let Err(e) = self
+                    .db
+                    .collection::<Document>("seq_reservations")
+                    .delete_many(doc! { "_id": { "$in": &tx.reservations } }, None)
+                    .await
+                {
+                    eprintln!("failed to release sequence reservations: {}", e);
+                }
+            }
+            match res {
+                Err(e) if is_write_conflict(&e) && attempts < MAX_TRANSACTION_RETRIES => {
+                    attempts += 1;
+                    tokio::time::sleep(std::time::Duration::from_millis(20 * attempts)).await;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    async fn commit(&self, tx: &mut Transaction) -> Result<(), Error> {
+        if !tx.reservations.is_empty() {
+            self.db
+                .collection::<Document>("seq_reservations")
+                .delete_many_with_session(doc! { "_id": { "$in": &tx.reservations } }, None, &mut tx.session)
+                .await
+                .map_err(|e| write_error("failed to release sequence reservations", e))?;
+        }
+        if !tx.events.is_empty() {
+            let mut docs = Vec::with_capacity(tx.events.len());
+            for event in &tx.events {
+                let mut d = to_document(event).map_err(|e| Error::new("failed to write outbox").with_cause(e))?;
+                d.remove("id");
+                d.insert("version", event.version as i64);
+                d.insert("occurred_at", event.occurred_at);
+                d.insert("dispatched_at", Bson::Null);
+                docs.push(d);
+            }
+            self.db
+                .collection::<Document>("outbox")
+                .insert_many_with_session(docs, None, &mut tx.session)
+                .await
+                .map_err(|e| write_error("failed to write outbox", e))?;
+        }
+        tx.session.commit_transaction().await.map_err(|e| {
+            tx.commit_unknown = e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT);
+            write_error("failed to commit transaction", e)
+        })
+    }
+
+    async fn audit_in(
+        &self,
+        tx: &mut Transaction,
+        action: AuditAction,
+        entity: AuditEntity,
+        id: &str,
+        changes: Vec<FieldChange>,
+    ) -> Result<(), Error> {
+        let entry = AuditEntry::new(&tx.actor, action, entity, id, changes);
+        let mut d = to_document(&entry).map_err(|e| Error::new("failed to append audit entry").with_cause(e))?;
+        d.remove("id");
+        d.insert("at", entry.at);
+        self.db
+            .collection::<Document>("audit_log")
+            .insert_one_with_session(d, None, &mut tx.session)
+            .await
+            .map_err(|e| write_error("failed to append audit entry", e))?;
+        Ok(())
+    }
+
+    // before为None表示创建或恢复, after为None表示删除
+    // 同时保存修改前后的快照, 修改前的版本通常已经保存过, 只有升级前的旧数据才会补上
+    async fn dog_changed_in(
+        &self,
+        tx: &mut Transaction,
+        action: AuditAction,
+        id: &str,
+        before: Option<&Dog>,
+        after: Option<&Dog>,
+    ) -> Result<(), Error> {
+        let changes = audit::diff(before.map(audit::dog_snapshot).as_ref(), after.map(audit::dog_snapshot).as_ref());
+        self.audit_in(tx, action, AuditEntity::Dog, id, changes).await?;
+        for (dog, actor) in [(before, None), (after, Some(tx.actor.clone()))] {
+            let Some(dog) = dog else {
+                continue;
+            };
+            let version = DogVersion {
+                dog_id: id.to_owned(),
+                version: dog.version,
+                dog: DogCreate::from(dog),
+                actor,
+                at: dog.updated_at,
+            };
+            self.save_dog_version_in(tx, &version).await?;
+        }
+        Ok(())
+    }
+
+    // 同一版本只保存一次, 已存在时忽略
+    async fn save_dog_version_in(&self, tx: &mut Transaction, version: &DogVersion) -> Result<(), Error> {
+        let mut d = to_document(version).map_err(|e| Error::new("failed to save dog version").with_cause(e))?;
+        d.insert("version", version.version as i64);
+        d.insert("at", version.at);
+        self.db
+            .collection::<Document>("dog_versions")
+            .update_one_with_session(
+                doc! { "dog_id": &version.dog_id, "version": version.version as i64 },
+                doc! { "$setOnInsert": d },
+                UpdateOptions::builder().upsert(true).build(),
+                &mut tx.session,
+            )
+            .await
+            .map_err(|e| write_error("failed to save dog version", e))?;
+        Ok(())
+    }
+
+    async fn breed_changed_in(
+        &self,
+        tx: &mut Transaction,
+        action: AuditAction,
+        id: &str,
+        before: Option<&Breed>,
+        after: Option<&Breed>,
+    ) -> Result<(), Error> {
+        let changes = audit::diff(before.map(audit::breed_snapshot).as_ref(), after.map(audit::breed_snapshot).as_ref());
+        self.audit_in(tx, action, AuditEntity::Breed, id, changes).await
+    }
+
+    async fn dog_in(&self, tx: &mut Transaction, id: ObjectId) -> Result<Option<Dog>, Error> {
+        self.db
+            .collection::<Dog>("dogs")
+            .find_one_with_session(
+                doc! { "_id": id },
+                FindOneOptions::builder().projection(Dog::projection()).build(),
+                &mut tx.session,
+            )
+            .await
+            .map_err(|e| write_error("failed to get dog", e))
+    }
+
+    async fn breed_in(&self, tx: &mut Transaction, id: ObjectId) -> Result<Option<Breed>, Error> {
+        self.db
+            .collection::<Breed>("breeds")
+            .find_one_with_session(
+                doc! { "_id": id },
+                FindOneOptions::builder().projection(Breed::projection()).build(),
+                &mut tx.session,
+            )
+            .await
+            .map_err(|e| write_error("failed to get breed", e))
+    }
+
+    // 狗狗被删除或转让后, 为原主人留下删除记录, 与狗狗的修改使用同一个序号
+    async fn add_tombstone(&self, tx: &mut Transaction, id: ObjectId, owner_id: &str, seq: i64) -> Result<(), Error> {
+        self.db
+            .collection::<Document>("dog_tombstones")
+            .insert_one_with_session(
+                doc! { "dog_id": id.to_string(), "owner_id": owner_id, "seq": seq, "deleted_at": Utc::now() },
+                None,
+                &mut tx.session,
+            )
+            .await
+            .map_err(|e| write_error("failed to add dog tombstone", e))?;
+        Ok(())
+    }
+
+    // 修改狗狗并分配新的变更序号, 删除或主人变化时为原主人留下删除记录, 同时写入领域事件, 狗狗不存在时返回false
+    async fn write_dog(&self, actor: &str, filter: Document, update: Document, event_type: EventType) -> Result<bool, Error> {
+        self.transaction(actor, async |tx| {
+            self.write_dog_in(tx, filter.clone(), update.clone(), event_type)
+                .await
+                .map(|v| v.is_some())
+        })
+        .await
+    }
+
+    // 在已有的事务中修改狗狗, 领域事件在提交时写入发件箱, 返回修改后的版本号, 狗狗不存在时返回None
+    async fn write_dog_in(&self, tx: &mut Transaction, filter: Document, mut update: Document, event_type: EventType) -> Result<Option<u64>, Error> {
+        let new_owner = update
+            .get_document("$set")
+            .ok()
+            .and_then(|s| s.get_str("owner_id").ok())
+            .map(str::to_owned);
+        let fields = changed_fields(&update);
+        let seq = self.next_seq(tx).await?;
+        if let Ok(set) = update.get_document_mut("$set") {
+            set.insert("seq", seq);
+        }
+        let Some(before) = self
+            .db
+            .collection::<Dog>("dogs")
+            .find_one_and_update_with_session(
+                filter,
+                update,
+                FindOneAndUpdateOptions::builder()
+                    .projection(Dog::projection())
+                    .return_document(ReturnDocument::Before)
+                    .build(),
+                &mut tx.session,
+            )
+            .await
+            .map_err(|e| write_error("failed to update dog", e))?
+        else {
+            return Ok(None);
+        };
+        let id = ObjectId::parse_str(&before.id).map_err(|e| Error::new("failed to update dog").with_cause(e))?;
+        let after = self.dog_in(tx, id).await?;
+        // 恢复前的狗狗不可见, 与创建一样只记录恢复后的内容
+        match event_type {
+            EventType::DogDeleted => self.dog_changed_in(tx, AuditAction::Delete, &before.id, Some(&before), None).await?,
+            EventType::DogRestored => self.dog_changed_in(tx, AuditAction::Restore, &before.id, None, after.as_ref()).await?,
+            _ => {
+                self.dog_changed_in(tx, AuditAction::Update, &before.id, Some(&before), after.as_ref())
+                    .await?
+            }
+        }
+        let old_owner = before.owner_id.clone();
+        let owner = new_owner.clone().unwrap_or(old_owner.clone());
+        let version = before.version + 1;
+        // 修改了主人时只发出一个转让事件, 同时带上修改的字段
+        let (event_type, data) = match event_type {
+            EventType::DogUpdated if owner != old_owner => (EventType::DogTransferred, json!({ "from": old_owner, "to": owner, "fields": fields })),
+            EventType::DogUpdated => (event_type, json!({ "fields": fields })),
+            _ => (event_type, json!({})),
+        };
+        tx.events.push(DomainEvent::new(event_type, &id.to_string(), Some(&owner), version, data));
+        if event_type == EventType::DogDeleted || owner != old_owner {
+            self.add_tombstone(tx, id, &old_owner, seq).await?;
+        }
+        Ok(Some(version))
+    }
+
+    // 在已有的事务中创建狗狗, 返回id
+    async fn insert_dog(&self, tx: &mut Transaction, dog: &DogCreate) -> Result<ObjectId, Error> {
+        let mut d = Document::try_from(dog)?;
+        d.insert("seq", self.next_seq(tx).await?);
+        let res = self
+            .db
+            .collection::<Document>("dogs")
+            .insert_one_with_session(d, None, &mut tx.session)
+            .await
+            .map_err(|e| write_error("failed to create dog", e))?;
+        let id = res
+            .inserted_id
+            .as_object_id()
+            .ok_or(Error::new("failed to create dog").with_cause("invalid inserted id"))?;
+        let created = self.dog_in(tx, id).await?;
+        self.dog_changed_in(tx, AuditAction::Create, &id.to_string(), None, created.as_ref())
+            .await?;
+        tx.events.push(DomainEvent::new(
+            EventType::DogCreated,
+            &id.to_string(),
+            Some(&dog.owner_id),
+            1,
+            json!({}),
+        ));
+        Ok(id)
+    }
+
+    async fn dogs_by_ids(&self, ids: &[ObjectId]) -> Result<Vec<Dog>, Error> {
+        let mut dogs = self
+            .db
+            .collection::<Dog>("dogs")
+            .find(
+                doc! { "_id": { "$in": ids } },
+                FindOptions::builder().projection(Dog::projection()).build(),
+            )
+            .await
+            .map_err(|e| Error::new("failed to get created dogs").with_cause(e))?
+            .try_collect::<Vec<Dog>>()
+            .await
+            .map_err(|e| Error::new("failed to get created dogs").with_cause(e))?
+            .into_iter()
+            .map(|d| (d.id.clone(), d))
+            .collect::<HashMap<String, Dog>>();
+        // 按传入的顺序返回
+        ids.iter()
+            .map(|id| dogs.remove(&id.to_hex()).ok_or(Error::new("created dog not exists")))
+            .collect()
+    }
+
+    // 每个狗狗只修改一次, 和其它修改一样分配变更序号, 保存版本和审计日志
+    async fn repoint_breed_in(&self, tx: &mut Transaction, from: &str, to: &Breed) -> Result<u64, Error> {
+        let snapshot = to_bson(&BreedQuery::from(to)).map_err(|e| Error::new("failed to repoint dogs").with_cause(e))?;
+        let dogs = self
+            .db
+            .collection::<Document>("dogs")
+            .find_with_session(
+                doc! { "$or": [{ "breed.id": from }, { "breed_components.breed.id": from }] },
+                FindOptions::builder().projection(doc! { "breed": 1, "breed_components": 1 }).build(),
+                &mut tx.session,
+            )
+            .await
+            .map_err(|e| write_error("failed to repoint dogs", e))?
+            .stream(&mut tx.session)
+            .try_collect::<Vec<Document>>()
+            .await
+            .map_err(|e| write_error("failed to repoint dogs", e))?;
+        self.reserve_seqs(tx, dogs.len() as i64).await?;
+        let refers = |b: &Bson| b.as_document().and_then(|b| b.get_str("id").ok()) == Some(from);
+        let mut repointed = 0;
+        for mut d in dogs {
+            let Ok(id) = d.get_object_id("_id") else {
+                continue;
+            };
+            let mut set = doc! { "updated_at": Utc::now() };
+            if d.get("breed").is_some_and(refers) {
+                set.insert("breed", snapshot.clone());
+            }
+            if let Ok(components) = d.get_array_mut("breed_components") {
+                for c in components.iter_mut().filter_map(Bson::as_document_mut) {
+                    if c.get("breed").is_some_and(refers) {
+                        c.insert("breed", snapshot.clone());
+                    }
+                }
+                set.insert("breed_components", components.clone());
+            }
+            let update = doc! { "$set": set, "$inc": { "version": 1 } };
+            if self.write_dog_in(tx, doc! { "_id": id }, update, EventType::DogUpdated).await?.is_some() {
+                repointed += 1;
+            }
+        }
+        // 只更新狗狗中的快照时, 子品种不变
+        if from == to.id {
+            return Ok(repointed);
+        }
+        let breeds = self
+            .db
+            .collection::<Breed>("breeds")
+            .find_with_session(
+                doc! { "parent_id": from },
+                FindOptions::builder().projection(Breed::projection()).build(),
+                &mut tx.session,
+            )
+            .await
+            .map_err(|e| write_error("failed to repoint sub-varieties", e))?
+            .stream(&mut tx.session)
+            .try_collect::<Vec<Breed>>()
+            .await
+            .map_err(|e| write_error("failed to repoint sub-varieties", e))?;
+        for before in breeds {
+            let id = ObjectId::parse_str(&before.id).map_err(|e| Error::new("failed to repoint sub-varieties").with_cause(e))?;
+            let Some(after) = self
+                .db
+                .collection::<Breed>("breeds")
+                .find_one_and_update_with_session(
+                    doc! { "_id": id },
+                    doc! { "$set": { "parent_id": &to.id, "updated_at": Utc::now() }, "$inc": { "version": 1 } },
+                    FindOneAndUpdateOptions::builder()
+                        .projection(Breed::projection())
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                    &mut tx.session,
+                )
+                .await
+                .map_err(|e| write_error("failed to repoint sub-varieties", e))?
+            else {
+                continue;
+            };
+            self.breed_changed_in(tx, AuditAction::Update, &after.id, Some(&before), Some(&after))
+                .await?;
+            let data = json!({ "change": "updated", "fields": ["parent_id"] });
+            tx.events
+                .push(DomainEvent::new(EventType::BreedChanged, &after.id, None, after.version, data));
+        }
+        Ok(repointed)
+    }
+
+    // 用change stream监听发件箱中新写入的事件, 每个实例都能收到所有事件, 比轮询发件箱更及时
+    // 单机部署的MongoDB不支持change stream, 返回错误
+    pub async fn watch_events(&self) -> Result<impl Stream<Item = Result<DomainEvent, Error>>, Error> {
+        let stream = self
+            .db
+            .collection::<Document>("outbox")
+            .watch(vec![doc! { "$match": { "operationType": "insert" } }], None)
+            .await
+            .map_err(|e| Error::new("failed to watch outbox").with_cause(e))?;
+        Ok(stream.map(|change| {
+            let change = change.map_err(|e| Error::new("failed to watch outbox").with_cause(e))?;
+            let mut d = change
+                .full_document
+                .ok_or(Error::new("failed to watch outbox").with_cause("missing full document"))?;
+            if let Ok(id) = d.get_object_id("_id") {
+                d.insert("id", id.to_hex());
+            }
+            if let Ok(occurred_at) = d.get_datetime("occurred_at") {
+                d.insert("occurred_at", occurred_at.to_chrono().to_rfc3339());
+            }
+            from_document::<DomainEvent>(d).map_err(|e| Error::new("failed to watch outbox").with_cause(e))
+        }))
+    }
 }
 
 impl Repository for MongoDB {
-    async fn create_breed(&self, breed: &BreedCreate) -> Result<String, Error> {
-        let now = Local::now();
-        let d = doc! {
-            "name": &breed.name,
-            "category": &breed.category.to_string(),
-            "created_at": now.to_rfc3339(),
-            "updated_at": now.to_rfc3339(),
-        };
+    async fn create_breed_group(&self, group: &BreedGroupCreate) -> Result<String, Error> {
+        let mut d = to_document(group).map_err(|e| Error::new("failed to create breed group").with_cause(e))?;
+        d.insert("created_at", Utc::now());
+        d.insert("updated_at", Utc::now());
         let res = self
             .db
-            .collection::<Document>("breeds")
+            .collection::<Document>("breed_groups")
             .insert_one(d, None)
             .await
-            .map_err(|e| Error::new("failed to create breed").with_cause(e))?;
+            .map_err(|e| Error::new("failed to create breed group").with_cause(e))?;
         res.inserted_id
             .as_object_id()
-            .ok_or(Error::new("failed to create breed").with_cause("invalid inserted id"))
+            .ok_or(Error::new("failed to create breed group").with_cause("invalid inserted id"))
             .map(|id| id.to_string())
     }
 
-    async fn create_dog(&self, dog: &DogCreate) -> Result<Dog, Error> {
-        let dog = Document::try_from(dog)?;
-        let res = self
-            .db
-            .collection::<Document>("dogs")
-            .insert_one(dog, None)
+    async fn query_breed_groups(&self) -> Result<Vec<BreedGroup>, Error> {
+        self.db
+            .collection::<BreedGroup>("breed_groups")
+            .find(
+                doc! {},
+                FindOptions::builder()
+                    .projection(BreedGroup::projection())
+                    .sort(doc! { "fci_number": 1, "name": 1 })
+                    .build(),
+            )
             .await
-            .map_err(|e| Error::new("failed to create dog").with_cause(e))?;
+            .map_err(|e| Error::new("failed to query breed groups").with_cause(e))?
+            .try_collect::<Vec<BreedGroup>>()
+            .await
+            .map_err(|e| Error::new("failed to query breed groups").with_cause(e))
+    }
+
+    async fn create_breed(&self, actor: &str, breed: &BreedCreate) -> Result<String, Error> {
+        let name_key = search::normalize(&breed.name);
+        if let Some(existing_id) = self.find_breed_conflict(&breed.category.to_string(), &name_key, None).await? {
+            return Err(breed_conflict(Some(existing_id)));
+        }
+        let now = Utc::now();
+        let d = doc! {
+            "name": &breed.name,
+            "name_key": name_key,
+            "category": &breed.category.to_string(),
+            "names": to_document(&breed.names).map_err(|e| Error::new("failed to create breed").with_cause(e))?,
+            "aliases": &breed.aliases,
+            "attributes": to_bson(&breed.attributes).map_err(|e| Error::new("failed to create breed").with_cause(e))?,
+            "search_keys": search::search_keys(&breed.name, &breed.aliases.iter().chain(breed.names.values()).cloned().collect::<Vec<_>>()),
+            "group_id": &breed.group_id,
+            "parent_id": &breed.parent_id,
+            "created_at": now,
+            "updated_at": now,
+            "version": 1_i64,
+        };
+        self.transaction(actor, async |tx| {
+            let res = self
+                .db
+                .collection::<Document>("breeds")
+                .insert_one_with_session(d.clone(), None, &mut tx.session)
+                .await
+                .map_err(|e| {
+                    if is_duplicate_key(&e) {
+                        breed_conflict(None)
+                    } else {
+                        write_error("failed to create breed", e)
+                    }
+                })?;
+            let oid = res
+                .inserted_id
+                .as_object_id()
+                .ok_or(Error::new("failed to create breed").with_cause("invalid inserted id"))?;
+            let id = oid.to_string();
+            let created = self.breed_in(tx, oid).await?;
+            self.breed_changed_in(tx, AuditAction::Create, &id, None, created.as_ref()).await?;
+            tx.events
+                .push(DomainEvent::new(EventType::BreedChanged, &id, None, 1, json!({ "change": "created" })));
+            Ok(id)
+        })
+        .await
+    }
+
+    async fn create_dog(&self, actor: &str, dog: &DogCreate) -> Result<Dog, Error> {
+        let id = self.transaction(actor, async |tx| self.insert_dog(tx, dog).await).await?;
         self.db
             .collection("dogs")
-            .find_one(
-                doc! {"_id": res.inserted_id},
-                FindOneOptions::builder().projection(Dog::projection()).build(),
-            )
+            .find_one(doc! {"_id": id}, FindOneOptions::builder().projection(Dog::projection()).build())
             .await
             .map_err(|e| Error::new("failed to get created dog").with_cause(e))?
             .ok_or(Error::new("created dog not exists"))
     }
 
-    async fn delete_breed(&self, id: &str) -> Result<bool, Error> {
-        self.db
-            .collection::<Breed>("breeds")
-            .delete_one(
-                doc! {"_id": ObjectId::parse_str(id).map_err(|e| Error::new("failed to delete breed").with_cause(e))?},
-                None,
-            )
-            .await
-            .map_err(|e| Error::new("failed to delete breed").with_cause(e))
-            .map(|res| res.deleted_count > 0)
+    async fn create_dogs(&self, actor: &str, dogs: &[DogCreate]) -> Result<Vec<Dog>, Error> {
+        let ids = self
+            .transaction(actor, async |tx| {
+                self.reserve_seqs(tx, dogs.len() as i64).await?;
+                let mut ids = Vec::with_capacity(dogs.len());
+                for dog in dogs {
+                    ids.push(self.insert_dog(tx, dog).await?);
+                }
+                Ok(ids)
+            })
+            .await?;
+        self.dogs_by_ids(&ids).await
     }
 
-    async fn delete_dog(&self, id: &str) -> Result<bool, Error> {
-        self.db
-            .collection::<Breed>("dogs")
-            .delete_one(
-                doc! {"_id": ObjectId::parse_str(id).map_err(|e| Error::new("failed to delete dog").with_cause(e))?},
-                None,
-            )
-            .await
-            .map_err(|e| Error::new("failed to delete dog").with_cause(e))
-            .map(|res| res.deleted_count > 0)
+    async fn delete_breed(&self, actor: &str, id: &str) -> Result<bool, Error> {
+        let oid = breed_id(id)?;
+        self.transaction(actor, async |tx| {
+            let Some(deleted) = self
+                .db
+                .collection::<Breed>("breeds")
+                .find_one_and_delete_with_session(
+                    doc! {"_id": oid},
+                    FindOneAndDeleteOptions::builder().projection(Breed::projection()).build(),
+                    &mut tx.session,
+                )
+                .await
+                .map_err(|e| write_error("failed to delete breed", e))?
+            else {
+                return Ok(false);
+            };
+            self.breed_changed_in(tx, AuditAction::Delete, id, Some(&deleted), None).await?;
+            let event = DomainEvent::new(EventType::BreedChanged, id, None, deleted.version + 1, json!({ "change": "deleted" }));
+            tx.events.push(event);
+            Ok(true)
+        })
+        .await
     }
 
-    async fn update_dog(&self, id: &str, dog: &DogUpdate) -> Result<bool, Error> {
+    async fn update_breed(&self, actor: &str, id: &str, breed: &BreedUpdate, version: Option<u64>) -> Result<bool, Error> {
         let mut update = doc! {};
-        if let Some(name) = &dog.name {
+        if let Some(name) = &breed.name {
             update.insert("name", name);
         }
-        if let Some(gender) = &dog.gender {
-            update.insert("gender", gender);
-        }
-        if let Some(breed) = &dog.breed {
-            update.insert("breed", &breed.id);
+        if let Some(category) = &breed.category {
+            update.insert("category", category.to_string());
         }
-        if let Some(birthday) = &dog.birthday {
-            update.insert("birthday", birthday);
+        if let Some(names) = &breed.names {
+            update.insert(
+                "names",
+                to_document(names).map_err(|e| Error::new("failed to update breed").with_cause(e))?,
+            );
         }
-        if let Some(is_sterilized) = &dog.is_sterilized {
-            update.insert("is_sterilized", is_sterilized);
+        if let Some(aliases) = &breed.aliases {
+            update.insert("aliases", aliases);
         }
-        if let Some(introduction) = &dog.introduction {
-            update.insert("introduction", introduction);
+        if let Some(attributes) = &breed.attributes {
+            update.insert(
+                "attributes",
+                to_bson(attributes).map_err(|e| Error::new("failed to update breed").with_cause(e))?,
+            );
         }
-        if let Some(owner_id) = &dog.owner_id {
-            update.insert("owner_id", owner_id);
+        let mut unset = doc! {};
+        for (field, value) in [("group_id", &breed.group_id), ("parent_id", &breed.parent_id)] {
+            match value {
+                Some(Some(id)) => {
+                    update.insert(field, id);
+                }
+                Some(None) => {
+                    unset.insert(field, "");
+                }
+                None => {}
+            }
         }
-        if let Some(tags) = &dog.tags {
-            update.insert("tags", tags);
+        if update.is_empty() && unset.is_empty() {
+            return Ok(false);
         }
-        if let Some(portrait_id) = &dog.portrait_id {
-            update.insert("portrait_id", portrait_id);
+        update.insert("updated_at", Utc::now());
+        let id = breed_id(id)?;
+        self.transaction(actor, async |tx| {
+            let Some(before) = self.breed_in(tx, id).await? else {
+                return Ok(false);
+            };
+            let mut update = update.clone();
+            if breed.name.is_some() || breed.category.is_some() {
+                let name_key = search::normalize(breed.name.as_ref().unwrap_or(&before.name));
+                let category = breed.category.as_ref().unwrap_or(&before.category).to_string();
+                if let Some(existing_id) = self.find_breed_conflict(&category, &name_key, Some(id)).await? {
+                    return Err(breed_conflict(Some(existing_id)));
+                }
+                update.insert("name_key", name_key);
+            }
+            let mut update = doc! { "$set": update, "$inc": { "version": 1 } };
+            if !unset.is_empty() {
+                update.insert("$unset", unset.clone());
+            }
+            let fields = changed_fields(&update);
+            let updated = self
+                .db
+                .collection::<Breed>("breeds")
+                .find_one_and_update_with_session(
+                    versioned(id, version),
+                    update,
+                    FindOneAndUpdateOptions::builder()
+                        .projection(Breed::projection())
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                    &mut tx.session,
+                )
+                .await
+                .map_err(|e| {
+                    if is_duplicate_key(&e) {
+                        breed_conflict(None)
+                    } else {
+                        write_error("failed to update breed", e)
+                    }
+                })?;
+            let Some(updated) = updated else {
+                return Ok(false);
+            };
+            // 名字变化后重新生成检索关键字
+            if breed.name.is_some() || breed.names.is_some() || breed.aliases.is_some() {
+                let keys = search::search_keys(
+                    &updated.name,
+                    &updated.aliases.iter().chain(updated.names.values()).cloned().collect::<Vec<_>>(),
+                );
+                self.db
+                    .collection::<Breed>("breeds")
+                    .update_one_with_session(doc! { "_id": id }, doc! { "$set": { "search_keys": keys } }, None, &mut tx.session)
+                    .await
+                    .map_err(|e| write_error("failed to update breed", e))?;
+            }
+            self.breed_changed_in(tx, AuditAction::Update, &updated.id, Some(&before), Some(&updated))
+                .await?;
+            let data = json!({ "change": "updated", "fields": fields });
+            tx.events
+                .push(DomainEvent::new(EventType::BreedChanged, &updated.id, None, updated.version, data));
+            // 狗狗中保存了品种的快照, 快照中的字段变化时一起更新
+            if BreedQuery::from(&before) != BreedQuery::from(&updated) {
+                self.repoint_breed_in(tx, &updated.id, &updated).await?;
+            }
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn merge_breeds(&self, actor: &str, from: &str, into: &str) -> Result<Option<u64>, Error> {
+        let (from_id, into_id) = (breed_id(from)?, breed_id(into)?);
+        self.transaction(actor, async |tx| {
+            let (Some(duplicate), Some(target)) = (self.breed_in(tx, from_id).await?, self.breed_in(tx, into_id).await?) else {
+                return Ok(None);
+            };
+            let mut merged = target.clone();
+            merged.absorb(&duplicate);
+            let keys = search::search_keys(
+                &merged.name,
+                &merged.aliases.iter().chain(merged.names.values()).cloned().collect::<Vec<_>>(),
+            );
+            let set = doc! {
+                "aliases": &merged.aliases,
+                "names": to_document(&merged.names).map_err(|e| Error::new("failed to merge breeds").with_cause(e))?,
+                "search_keys": keys,
+                "updated_at": Utc::now(),
+            };
+            let merged = self
+                .db
+                .collection::<Breed>("breeds")
+                .find_one_and_update_with_session(
+                    versioned(into_id, Some(target.version)),
+                    doc! { "$set": set, "$inc": { "version": 1 } },
+                    FindOneAndUpdateOptions::builder()
+                        .projection(Breed::projection())
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                    &mut tx.session,
+                )
+                .await
+                .map_err(|e| write_error("failed to merge breeds", e))?
+                .ok_or(Error::conflict(WRITE_CONFLICT))?;
+            self.breed_changed_in(tx, AuditAction::Update, into, Some(&target), Some(&merged)).await?;
+            let data = json!({ "change": "updated", "fields": ["names", "aliases"] });
+            tx.events
+                .push(DomainEvent::new(EventType::BreedChanged, into, None, merged.version, data));
+            let repointed = self.repoint_breed_in(tx, from, &merged).await?;
+            self.db
+                .collection::<Document>("breeds")
+                .delete_one_with_session(doc! { "_id": from_id }, None, &mut tx.session)
+                .await
+                .map_err(|e| write_error("failed to merge breeds", e))?;
+            let mut changes = audit::diff(Some(&audit::breed_snapshot(&duplicate)), None);
+            changes.push(FieldChange {
+                field: "merged_into".to_owned(),
+                before: serde_json::Value::Null,
+                after: serde_json::Value::from(into),
+            });
+            self.audit_in(tx, AuditAction::Merge, AuditEntity::Breed, from, changes).await?;
+            let data = json!({ "change": "deleted", "merged_into": into });
+            tx.events
+                .push(DomainEvent::new(EventType::BreedChanged, from, None, duplicate.version + 1, data));
+            Ok(Some(repointed))
+        })
+        .await
+    }
+
+    // 软删除, 只做标记, 超过保留期后由purge_dogs彻底删除
+    async fn delete_dog(&self, actor: &str, id: &str, version: Option<u64>) -> Result<bool, Error> {
+        let id = ObjectId::parse_str(id).map_err(|e| Error::new("failed to delete dog").with_cause(e))?;
+        let now = Utc::now();
+        let update = doc! { "$set": { "deleted_at": now, "updated_at": now }, "$inc": { "version": 1 } };
+        self.write_dog(actor, live_dog(id, version), update, EventType::DogDeleted).await
+    }
+
+    async fn restore_dog(&self, actor: &str, id: &str, owner_id: &str, deleted_after: DateTime<Utc>) -> Result<bool, Error> {
+        let id = ObjectId::parse_str(id).map_err(|e| Error::new("failed to restore dog").with_cause(e))?;
+        let filter = doc! { "_id": id, "owner_id": owner_id, "deleted_at": { "$gte": deleted_after } };
+        let update = doc! { "$set": { "updated_at": Utc::now() }, "$unset": { "deleted_at": "" }, "$inc": { "version": 1 } };
+        self.write_dog(actor, filter, update, EventType::DogRestored).await
+    }
+
+    // 同时删除历史版本, 两者在一个事务中删除, 避免只删掉其中之一
+    async fn purge_dogs(&self, deleted_before: DateTime<Utc>) -> Result<u64, Error> {
+        self.transaction(audit::SYSTEM_ACTOR, async |tx| {
+            let dogs = self.db.collection::<Document>("dogs");
+            let filter = doc! { "deleted_at": { "$lt": deleted_before } };
+            let ids = dogs
+                .distinct_with_session("_id", filter, None, &mut tx.session)
+                .await
+                .map_err(|e| write_error("failed to purge dogs", e))?
+                .into_iter()
+                .filter_map(|id| id.as_object_id())
+                .collect::<Vec<ObjectId>>();
+            if ids.is_empty() {
+                return Ok(0);
+            }
+            let purged = dogs
+                .delete_many_with_session(doc! { "_id": { "$in": &ids } }, None, &mut tx.session)
+                .await
+                .map_err(|e| write_error("failed to purge dogs", e))?
+                .deleted_count;
+            let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<String>>();
+            self.db
+                .collection::<Document>("dog_versions")
+                .delete_many_with_session(doc! { "dog_id": { "$in": &ids } }, None, &mut tx.session)
+                .await
+                .map_err(|e| write_error("failed to purge dog versions", e))?;
+            Ok(purged)
+        })
+        .await
+    }
+
+    async fn update_dog(&self, actor: &str, id: &str, dog: &DogUpdate, version: Option<u64>) -> Result<bool, Error> {
+        let Some(update) = dog_update(dog)? else {
+            return Ok(false);
+        };
+        let id = ObjectId::parse_str(id).map_err(|e| Error::new("failed to update dog").with_cause(e))?;
+        self.write_dog(actor, live_dog(id, version), update, EventType::DogUpdated).await
+    }
+
+    // 全部修改成功才提交, 否则回滚, 修改前后的版本号都在事务中读取, 没有要修改的字段时只检查版本号
+    async fn update_dogs(&self, actor: &str, updates: &[DogBatchUpdate]) -> Result<Vec<Option<(bool, u64)>>, Error> {
+        self.transaction(actor, async |tx| {
+            self.reserve_seqs(tx, updates.len() as i64).await?;
+            let mut updated = Vec::with_capacity(updates.len());
+            for item in updates {
+                let id = ObjectId::parse_str(&item.id).map_err(|e| Error::new("failed to update dog").with_cause(e))?;
+                // 没有要修改的字段时不写入, 但仍然检查狗狗存在且版本号一致
+                let Some(update) = dog_update(&item.dog)? else {
+                    let found = self
+                        .db
+                        .collection::<Dog>("dogs")
+                        .find_one_with_session(
+                            live_dog(id, item.version),
+                            FindOneOptions::builder().projection(Dog::projection()).build(),
+                            &mut tx.session,
+                        )
+                        .await
+                        .map_err(|e| write_error("failed to update dog", e))?;
+                    updated.push(found.map(|d| (false, d.version)));
+                    continue;
+                };
+                let version = self.write_dog_in(tx, live_dog(id, item.version), update, EventType::DogUpdated).await?;
+                updated.push(version.map(|v| (true, v)));
+            }
+            tx.rollback = !updated.iter().all(Option::is_some);
+            Ok(updated)
+        })
+        .await
+    }
+
+    async fn replace_dog(&self, actor: &str, id: &str, dog: &DogCreate, version: Option<u64>) -> Result<bool, Error> {
+        let mut set = Document::try_from(dog)?;
+        set.remove("created_at");
+        set.remove("version");
+        let mut unset = doc! {};
+        if dog.portrait_id.is_none() {
+            set.remove("portrait_id");
+            unset.insert("portrait_id", "");
         }
-        if !update.is_empty() {
-            update.insert("updated_at", Local::now().to_rfc3339());
+        let mut update = doc! { "$set": set, "$inc": { "version": 1 } };
+        if !unset.is_empty() {
+            update.insert("$unset", unset);
         }
-        Ok(self
-            .db
-            .collection::<DogUpdate>("dogs")
-            .update_one(
-                doc! {
-                    "_id": ObjectId::parse_str(id).map_err(|e| Error::new("failed to update dog").with_cause(e))?
-                },
-                doc! { "$set": update},
-                None,
-            )
-            .await
-            .map_err(|e| Error::new("failed to update dog").with_cause(e))?
-            .modified_count
-            > 0)
+        let id = ObjectId::parse_str(id).map_err(|e| Error::new("failed to replace dog").with_cause(e))?;
+        self.write_dog(actor, live_dog(id, version), update, EventType::DogUpdated).await
     }
 
     async fn query_breeds(&self, query: &BreedQuery) -> Result<(Vec<Breed>, i64), Error> {
         let mut q = doc! {};
+        if let Some(id) = &query.id {
+            q.insert("_id", breed_id(id)?);
+        }
         if let Some(category) = &query.category {
             q.insert("category", category.to_string());
         }
+        if let Some(group_id) = &query.group_id {
+            q.insert("group_id", group_id);
+        }
+        if let Some(parent_id) = &query.parent_id {
+            q.insert("parent_id", parent_id);
+        }
+        if let Some(created_after) = query.created_after {
+            q.insert("created_at", doc! { "$gte": created_after });
+        }
+        if let Some(updated_after) = query.updated_after {
+            q.insert("updated_at", doc! { "$gte": updated_after });
+        }
         let count = self
             .db
             .collection::<Breed>("breeds")
@@ -188,18 +1507,7 @@ impl Repository for MongoDB {
         let breeds = self
             .db
             .collection::<Breed>("breeds")
-            .find(
-                q,
-                FindOptions::builder()
-                    .projection(doc! {
-                        "id": { "$toString": "$_id" },
-                        "category": 1,
-                        "name": 1,
-                        "created_at": 1,
-                        "updated_at": 1,
-                    })
-                    .build(),
-            )
+            .find(q, FindOptions::builder().projection(Breed::projection()).build())
             .await
             .map_err(|e| Error::new("failed to query breeds").with_cause(e))?
             .try_collect::<Vec<Breed>>()
@@ -208,16 +1516,40 @@ impl Repository for MongoDB {
         Ok((breeds, count as i64))
     }
 
+    async fn search_breeds(&self, keyword: &str) -> Result<Vec<Breed>, Error> {
+        self.db
+            .collection::<Breed>("breeds")
+            .find(
+                doc! { "search_keys": { "$regex": escape_regex(keyword) } },
+                FindOptions::builder().projection(Breed::projection()).build(),
+            )
+            .await
+            .map_err(|e| Error::new("failed to search breeds").with_cause(e))?
+            .try_collect::<Vec<Breed>>()
+            .await
+            .map_err(|e| Error::new("failed to search breeds").with_cause(e))
+    }
+
     async fn query_dogs(&self, query: &DogQuery) -> Result<Vec<Dog>, Error> {
-        let mut q = doc! {};
-        if let Some(owner_id) = &query.owner_id {
-            q.insert("owner_id", owner_id);
+        let mut q = dog_filter(query)?;
+        let mut breed_filters = vec![];
+        if let Some(breed_id) = &query.breed_id {
+            breed_filters.push(doc! { "$or": [{ "breed.id": breed_id }, { "breed_components.breed.id": breed_id }] });
         }
-        if let Some(id_in) = &query.id_in {
-            q.insert(
-                "_id",
-                doc! { "$in": id_in.deref().iter().map(|id| ObjectId::parse_str(id).map_err(|e| Error::new("failed to query my dogs").with_cause(e))).collect::<Result<Vec<_>, Error>>()? },
-            );
+        if let Some(breed_group_id) = &query.breed_group_id {
+            let breed_ids = self
+                .db
+                .collection::<Document>("breeds")
+                .distinct("_id", doc! { "group_id": breed_group_id }, None)
+                .await
+                .map_err(|e| Error::new("failed to query my dogs").with_cause(e))?
+                .into_iter()
+                .filter_map(|id| id.as_object_id().map(|id| id.to_string()))
+                .collect::<Vec<String>>();
+            breed_filters.push(doc! { "$or": [{ "breed.id": { "$in": &breed_ids } }, { "breed_components.breed.id": { "$in": &breed_ids } }] });
+        }
+        if !breed_filters.is_empty() {
+            q.insert("$and", breed_filters);
         }
         let options = FindOptions::builder()
             .projection(Dog::projection())
@@ -306,23 +1638,402 @@ impl Repository for MongoDB {
     }
 
     async fn exists_dog(&self, query: &DogQuery) -> Result<bool, Error> {
+        let q = dog_filter(query)?;
+        Ok(self
+            .db
+            .collection::<Dog>("dogs")
+            .count_documents(q, None)
+            .await
+            .map_err(|e| Error::new("failed to query my dogs").with_cause(e))?
+            > 0)
+    }
+
+    async fn dog_versions(&self, dog_id: &str) -> Result<Vec<DogVersion>, Error> {
+        self.db
+            .collection::<DogVersion>("dog_versions")
+            .find(
+                doc! { "dog_id": dog_id },
+                FindOptions::builder()
+                    .projection(DogVersion::projection())
+                    .sort(doc! { "version": -1 })
+                    .build(),
+            )
+            .await
+            .map_err(|e| Error::new("failed to query dog versions").with_cause(e))?
+            .try_collect::<Vec<DogVersion>>()
+            .await
+            .map_err(|e| Error::new("failed to query dog versions").with_cause(e))
+    }
+
+    async fn dog_version(&self, dog_id: &str, version: u64) -> Result<Option<DogVersion>, Error> {
+        self.db
+            .collection::<DogVersion>("dog_versions")
+            .find_one(
+                doc! { "dog_id": dog_id, "version": version as i64 },
+                FindOneOptions::builder().projection(DogVersion::projection()).build(),
+            )
+            .await
+            .map_err(|e| Error::new("failed to query dog version").with_cause(e))
+    }
+
+    async fn query_audit(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, Error> {
         let mut q = doc! {};
-        if let Some(id) = &query.id {
+        if let Some(entity) = &query.entity {
             q.insert(
-                "_id",
-                ObjectId::parse_str(id).map_err(|e| Error::new("failed to query my dogs").with_cause(e))?,
+                "entity",
+                to_bson(entity).map_err(|e| Error::new("failed to query audit log").with_cause(e))?,
             );
         }
-        if let Some(owner_id) = &query.owner_id {
-            q.insert("owner_id", owner_id);
+        if let Some(entity_id) = &query.entity_id {
+            q.insert("entity_id", entity_id);
         }
-        Ok(self
+        if let Some(actor) = &query.actor {
+            q.insert("actor", actor);
+        }
+        let options = FindOptions::builder()
+            .projection(doc! {
+                "id": { "$toString": "$_id" },
+                "actor": 1,
+                "action": 1,
+                "entity": 1,
+                "entity_id": 1,
+                "at": timestamp("at"),
+                "changes": 1,
+            })
+            .sort(doc! { "at": -1, "_id": -1 })
+            .skip(query.pagination.as_ref().map(|p| p.skip as u64))
+            .limit(query.pagination.as_ref().map(|p| p.limit))
+            .build();
+        self.db
+            .collection::<AuditEntry>("audit_log")
+            .find(q, options)
+            .await
+            .map_err(|e| Error::new("failed to query audit log").with_cause(e))?
+            .try_collect::<Vec<AuditEntry>>()
+            .await
+            .map_err(|e| Error::new("failed to query audit log").with_cause(e))
+    }
+
+    async fn dog_changes(&self, owner_id: &str, since: u64, limit: i64) -> Result<(Vec<Dog>, Vec<DogTombstone>), Error> {
+        // 只返回已经全部提交的变更, 之后提交的修改不会小于返回的序号
+        let filter = doc! { "owner_id": owner_id, "seq": { "$gt": since as i64, "$lte": self.seq_watermark().await? } };
+        let mut live = filter.clone();
+        live.insert("deleted_at", Bson::Null);
+        let dogs = self
             .db
             .collection::<Dog>("dogs")
-            .count_documents(q.clone(), None)
+            .find(
+                live,
+                FindOptions::builder()
+                    .projection(Dog::projection())
+                    .sort(doc! { "seq": 1 })
+                    .limit(limit)
+                    .build(),
+            )
             .await
-            .map_err(|e| Error::new("failed to query my dogs").with_cause(e))?
-            > 0)
+            .map_err(|e| Error::new("failed to query dog changes").with_cause(e))?
+            .try_collect::<Vec<Dog>>()
+            .await
+            .map_err(|e| Error::new("failed to query dog changes").with_cause(e))?;
+        let tombstones = self
+            .db
+            .collection::<DogTombstone>("dog_tombstones")
+            .find(
+                filter,
+                FindOptions::builder()
+                    .projection(doc! { "id": "$dog_id", "seq": 1, "deleted_at": timestamp("deleted_at") })
+                    .sort(doc! { "seq": 1 })
+                    .limit(limit)
+                    .build(),
+            )
+            .await
+            .map_err(|e| Error::new("failed to query dog changes").with_cause(e))?
+            .try_collect::<Vec<DogTombstone>>()
+            .await
+            .map_err(|e| Error::new("failed to query dog changes").with_cause(e))?;
+        Ok((dogs, tombstones))
+    }
+
+    // 逐个占用未投递且未被占用的事件, 多个实例同时投递时不会重复发送, 占用过期后可以被重新占用
+    async fn claim_events(&self, now: DateTime<Utc>, lease: chrono::Duration, limit: usize) -> Result<Vec<DomainEvent>, Error> {
+        let options = FindOneAndUpdateOptions::builder()
+            .projection(doc! {
+                "id": { "$toString": "$_id" },
+                "type": 1,
+                "aggregate_id": 1,
+                "owner_id": 1,
+                "version": 1,
+                "occurred_at": timestamp("occurred_at"),
+                "data": 1,
+            })
+            .sort(doc! { "_id": 1 })
+            .return_document(ReturnDocument::After)
+            .build();
+        let mut events = vec![];
+        while events.len() < limit {
+            let claimed = self
+                .db
+                .collection::<DomainEvent>("outbox")
+                .find_one_and_update(
+                    doc! { "dispatched_at": Bson::Null, "$or": [{ "claimed_until": Bson::Null }, { "claimed_until": { "$lte": now } }] },
+                    doc! { "$set": { "claimed_until": now + lease } },
+                    options.clone(),
+                )
+                .await
+                .map_err(|e| Error::new("failed to claim events").with_cause(e))?;
+            let Some(claimed) = claimed else {
+                break;
+            };
+            events.push(claimed);
+        }
+        Ok(events)
+    }
+
+    async fn mark_events_dispatched(&self, ids: &[String]) -> Result<(), Error> {
+        let ids = ids
+            .iter()
+            .map(|id| ObjectId::parse_str(id).map_err(|e| Error::new("failed to mark events dispatched").with_cause(e)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.db
+            .collection::<Document>("outbox")
+            .update_many(doc! { "_id": { "$in": ids } }, doc! { "$set": { "dispatched_at": Utc::now() } }, None)
+            .await
+            .map_err(|e| Error::new("failed to mark events dispatched").with_cause(e))?;
+        Ok(())
+    }
+
+    async fn create_webhook(&self, webhook: &WebhookCreate) -> Result<Webhook, Error> {
+        let mut d = to_document(webhook).map_err(|e| Error::new("failed to create webhook").with_cause(e))?;
+        d.insert("created_at", Utc::now());
+        let res = self
+            .db
+            .collection::<Document>("webhooks")
+            .insert_one(d, None)
+            .await
+            .map_err(|e| Error::new("failed to create webhook").with_cause(e))?;
+        self.db
+            .collection::<Webhook>("webhooks")
+            .find_one(
+                doc! { "_id": res.inserted_id },
+                FindOneOptions::builder().projection(Webhook::projection()).build(),
+            )
+            .await
+            .map_err(|e| Error::new("failed to get created webhook").with_cause(e))?
+            .ok_or(Error::new("created webhook not exists"))
+    }
+
+    async fn webhooks(&self) -> Result<Vec<Webhook>, Error> {
+        self.db
+            .collection::<Webhook>("webhooks")
+            .find(
+                doc! {},
+                FindOptions::builder().projection(Webhook::projection()).sort(doc! { "_id": 1 }).build(),
+            )
+            .await
+            .map_err(|e| Error::new("failed to query webhooks").with_cause(e))?
+            .try_collect::<Vec<Webhook>>()
+            .await
+            .map_err(|e| Error::new("failed to query webhooks").with_cause(e))
+    }
+
+    async fn webhook(&self, id: &str) -> Result<Option<Webhook>, Error> {
+        let Ok(id) = ObjectId::parse_str(id) else {
+            return Ok(None);
+        };
+        self.db
+            .collection::<Webhook>("webhooks")
+            .find_one(doc! { "_id": id }, FindOneOptions::builder().projection(Webhook::projection()).build())
+            .await
+            .map_err(|e| Error::new("failed to get webhook").with_cause(e))
+    }
+
+    async fn delete_webhook(&self, id: &str) -> Result<bool, Error> {
+        let Ok(id) = ObjectId::parse_str(id) else {
+            return Ok(false);
+        };
+        self.db
+            .collection::<Document>("webhooks")
+            .delete_one(doc! { "_id": id }, None)
+            .await
+            .map_err(|e| Error::new("failed to delete webhook").with_cause(e))
+            .map(|res| res.deleted_count > 0)
+    }
+
+    async fn create_webhook_deliveries(&self, deliveries: &[WebhookDelivery]) -> Result<(), Error> {
+        let mut docs = Vec::with_capacity(deliveries.len());
+        for delivery in deliveries {
+            let mut d = to_document(delivery).map_err(|e| Error::new("failed to create webhook deliveries").with_cause(e))?;
+            d.remove("id");
+            d.insert("attempts", delivery.attempts as i64);
+            d.insert("next_attempt_at", delivery.next_attempt_at);
+            d.insert("created_at", delivery.created_at);
+            docs.push(d);
+        }
+        self.db
+            .collection::<Document>("webhook_deliveries")
+            .insert_many(docs, None)
+            .await
+            .map_err(|e| Error::new("failed to create webhook deliveries").with_cause(e))?;
+        Ok(())
+    }
+
+    async fn claim_webhook_deliveries(&self, now: DateTime<Utc>, lease: chrono::Duration, limit: usize) -> Result<Vec<WebhookDelivery>, Error> {
+        let mut deliveries = vec![];
+        while deliveries.len() < limit {
+            let claimed = self
+                .db
+                .collection::<WebhookDelivery>("webhook_deliveries")
+                .find_one_and_update(
+                    doc! { "status": "pending", "next_attempt_at": { "$lte": now } },
+                    doc! { "$set": { "next_attempt_at": now + lease } },
+                    FindOneAndUpdateOptions::builder()
+                        .projection(WebhookDelivery::projection())
+                        .sort(doc! { "next_attempt_at": 1 })
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                )
+                .await
+                .map_err(|e| Error::new("failed to claim webhook deliveries").with_cause(e))?;
+            let Some(claimed) = claimed else {
+                break;
+            };
+            deliveries.push(claimed);
+        }
+        Ok(deliveries)
+    }
+
+    async fn update_webhook_delivery(&self, delivery: &WebhookDelivery) -> Result<(), Error> {
+        let id = ObjectId::parse_str(&delivery.id).map_err(|e| Error::new("failed to update webhook delivery").with_cause(e))?;
+        let update = doc! {
+            "$set": {
+                "status": to_bson(&delivery.status).map_err(|e| Error::new("failed to update webhook delivery").with_cause(e))?,
+                "attempts": delivery.attempts as i64,
+                "next_attempt_at": delivery.next_attempt_at,
+                "last_error": &delivery.last_error,
+                "delivered_at": delivery.delivered_at,
+            }
+        };
+        self.db
+            .collection::<Document>("webhook_deliveries")
+            .update_one(doc! { "_id": id }, update, None)
+            .await
+            .map_err(|e| Error::new("failed to update webhook delivery").with_cause(e))?;
+        Ok(())
+    }
+
+    async fn query_webhook_deliveries(&self, query: &WebhookDeliveryQuery) -> Result<Vec<WebhookDelivery>, Error> {
+        let mut q = doc! {};
+        if let Some(webhook_id) = &query.webhook_id {
+            q.insert("webhook_id", webhook_id);
+        }
+        if let Some(status) = &query.status {
+            q.insert(
+                "status",
+                to_bson(status).map_err(|e| Error::new("failed to query webhook deliveries").with_cause(e))?,
+            );
+        }
+        let options = FindOptions::builder()
+            .projection(WebhookDelivery::projection())
+            .sort(doc! { "created_at": -1 })
+            .skip(query.pagination.as_ref().map(|p| p.skip as u64))
+            .limit(query.pagination.as_ref().map(|p| p.limit))
+            .build();
+        self.db
+            .collection::<WebhookDelivery>("webhook_deliveries")
+            .find(q, options)
+            .await
+            .map_err(|e| Error::new("failed to query webhook deliveries").with_cause(e))?
+            .try_collect::<Vec<WebhookDelivery>>()
+            .await
+            .map_err(|e| Error::new("failed to query webhook deliveries").with_cause(e))
+    }
+
+    async fn redeliver_webhook(&self, delivery_id: &str) -> Result<Option<WebhookDelivery>, Error> {
+        let Ok(id) = ObjectId::parse_str(delivery_id) else {
+            return Ok(None);
+        };
+        let Some(mut delivery) = self
+            .db
+            .collection::<WebhookDelivery>("webhook_deliveries")
+            .find_one(
+                doc! { "_id": id },
+                FindOneOptions::builder().projection(WebhookDelivery::projection()).build(),
+            )
+            .await
+            .map_err(|e| Error::new("failed to redeliver webhook").with_cause(e))?
+        else {
+            return Ok(None);
+        };
+        delivery.redeliver();
+        self.update_webhook_delivery(&delivery).await?;
+        Ok(Some(delivery))
+    }
+
+    async fn reserve_idempotency_key(&self, record: &IdempotencyRecord) -> Result<Option<IdempotencyRecord>, Error> {
+        let keys = self.db.collection::<Document>("idempotency_keys");
+        let filter = doc! { "user_id": &record.user_id, "key": &record.key };
+        // 过期的记录由TTL索引定期删除, 删除前也视为不存在, 锁定到期仍未完成的记录可以重新占用
+        let now = Utc::now();
+        let mut expired = filter.clone();
+        expired.insert(
+            "$or",
+            vec![
+                doc! { "expires_at": { "$lte": now } },
+                doc! { "response": Bson::Null, "locked_until": { "$lte": now } },
+            ],
+        );
+        keys.delete_one(expired, None)
+            .await
+            .map_err(|e| Error::new("failed to reserve idempotency key").with_cause(e))?;
+        let mut d = to_document(record).map_err(|e| Error::new("failed to reserve idempotency key").with_cause(e))?;
+        d.insert("created_at", record.created_at);
+        d.insert("locked_until", record.locked_until);
+        d.insert("expires_at", record.expires_at);
+        match keys.insert_one(d, None).await {
+            Ok(_) => Ok(None),
+            Err(e) if is_duplicate_key(&e) => self
+                .db
+                .collection::<IdempotencyRecord>("idempotency_keys")
+                .find_one(
+                    filter,
+                    FindOneOptions::builder()
+                        .projection(doc! {
+                            "_id": 0,
+                            "user_id": 1,
+                            "key": 1,
+                            "fingerprint": 1,
+                            "response": 1,
+                            "created_at": timestamp("created_at"),
+                            "locked_until": timestamp("locked_until"),
+                            "expires_at": timestamp("expires_at"),
+                        })
+                        .build(),
+                )
+                .await
+                .map_err(|e| Error::new("failed to reserve idempotency key").with_cause(e))?
+                .map(Some)
+                .ok_or(Error::conflict("idempotency key is being released, retry later")),
+            Err(e) => Err(Error::new("failed to reserve idempotency key").with_cause(e)),
+        }
+    }
+
+    async fn complete_idempotency_key(&self, user_id: &str, key: &str, response: &StoredResponse) -> Result<(), Error> {
+        let response = to_document(response).map_err(|e| Error::new("failed to save idempotent response").with_cause(e))?;
+        self.db
+            .collection::<Document>("idempotency_keys")
+            .update_one(doc! { "user_id": user_id, "key": key }, doc! { "$set": { "response": response } }, None)
+            .await
+            .map_err(|e| Error::new("failed to save idempotent response").with_cause(e))?;
+        Ok(())
+    }
+
+    async fn release_idempotency_key(&self, user_id: &str, key: &str) -> Result<(), Error> {
+        self.db
+            .collection::<Document>("idempotency_keys")
+            .delete_one(doc! { "user_id": user_id, "key": key, "response": Bson::Null }, None)
+            .await
+            .map_err(|e| Error::new("failed to release idempotency key").with_cause(e))?;
+        Ok(())
     }
 }
 
@@ -367,3 +2078,469 @@ impl Repository for MongoDB {
 //         println!("breeds: {:?}, total: {}", breeds, total);
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::service::Service;
+
+    #[test]
+    fn dog_filter_matches_the_requested_id() {
+        let id = ObjectId::new();
+        let q = dog_filter(&DogQuery {
+            id: Some(id.to_hex()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(q, doc! { "deleted_at": Bson::Null, "_id": { "$eq": id } });
+    }
+
+    #[test]
+    fn dog_filter_combines_id_with_other_filters() {
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+        let q = dog_filter(&DogQuery {
+            id: Some(a.to_hex()),
+            id_in: Some(vec![a.to_hex(), b.to_hex()]),
+            owner_id: Some("user-1".to_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            q,
+            doc! { "deleted_at": Bson::Null, "_id": { "$eq": a, "$in": [a, b] }, "owner_id": "user-1" }
+        );
+    }
+
+    #[test]
+    fn dog_filter_reports_unknown_ids_as_not_found() {
+        let e = dog_filter(&DogQuery {
+            id: Some("not-an-id".to_owned()),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert_eq!(e.kind(), crate::core::error::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn unparsable_breed_ids_are_not_found() {
+        let id = ObjectId::new();
+        assert_eq!(breed_id(&id.to_hex()).unwrap(), id);
+        assert_eq!(breed_id("not-an-id").unwrap_err().kind(), crate::core::error::ErrorKind::NotFound);
+    }
+
+    // 以下测试需要支持事务的MongoDB(副本集), 每个测试使用新的数据库:
+    // MONGODB_TEST_URI="mongodb://localhost:27021/?replicaSet=rs0&directConnection=true" cargo test -- --ignored
+    async fn test_database() -> Database {
+        let uri = std::env::var("MONGODB_TEST_URI").expect("MONGODB_TEST_URI not set");
+        let client = mongodb::Client::with_uri_str(uri).await.expect("failed to connect to mongodb");
+        let db = client.database(&format!("little_walk_test_{}", ObjectId::new()));
+        MongoDB::new(db.clone()).init().await.expect("failed to init mongodb");
+        db
+    }
+
+    async fn test_breed(repo: &MongoDB, name: &str) -> Breed {
+        let breed = serde_json::from_value::<BreedCreate>(json!({ "category": "Small", "name": name, "group_id": null, "parent_id": null })).unwrap();
+        let id = repo.create_breed("admin", &breed).await.unwrap();
+        repo.query_breeds(&BreedQuery {
+            id: Some(id),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .0
+        .pop()
+        .unwrap()
+    }
+
+    fn test_dog(breed: &Breed, components: &[&Breed]) -> DogCreate {
+        let components = components
+            .iter()
+            .map(|b| json!({ "breed": BreedQuery::from(*b), "percentage": 50 }))
+            .collect::<Vec<_>>();
+        serde_json::from_value(json!({
+            "owner_id": "user-1",
+            "name": "不二",
+            "gender": "Male",
+            "breed": BreedQuery::from(breed),
+            "breed_components": components,
+            "birthday": "2022-01-01",
+            "tags": [],
+            "portrait_id": null,
+        }))
+        .unwrap()
+    }
+
+    async fn dog_audit(repo: &MongoDB, id: &str) -> Vec<AuditEntry> {
+        repo.query_audit(&AuditQuery {
+            entity: Some(AuditEntity::Dog),
+            entity_id: Some(id.to_owned()),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn dog_versions_are_saved_with_each_change() {
+        let db = test_database().await;
+        let repo = MongoDB::new(db.clone());
+        let service = Service::new(MongoDB::new(db));
+        let breed = test_breed(&repo, "柯基").await;
+        let dog = service.create_dog("user-1", &test_dog(&breed, &[])).await.unwrap();
+        let update = DogUpdate {
+            name: Some("二二".to_owned()),
+            ..Default::default()
+        };
+        assert!(service.update_dog("user-2", &dog.id, &update, Some(1)).await.unwrap());
+        let versions = service.dog_versions(&dog.id).await.unwrap();
+        assert_eq!(versions.iter().map(|v| v.version).collect::<Vec<_>>(), [2, 1]);
+        assert_eq!(
+            versions.iter().map(|v| v.actor.as_deref()).collect::<Vec<_>>(),
+            [Some("user-2"), Some("user-1")]
+        );
+        assert_eq!((versions[0].dog.name.as_str(), versions[1].dog.name.as_str()), ("二二", "不二"));
+        assert_eq!(service.dog_version(&dog.id, 1).await.unwrap().dog.name, "不二");
+        assert_eq!(
+            service.dog_version(&dog.id, 3).await.unwrap_err().kind(),
+            crate::core::error::ErrorKind::NotFound
+        );
+        let audit = dog_audit(&repo, &dog.id).await;
+        assert_eq!(
+            audit.iter().map(|a| a.action).collect::<Vec<_>>(),
+            [AuditAction::Update, AuditAction::Create]
+        );
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn revert_dog_saves_the_old_content_as_a_new_version() {
+        let db = test_database().await;
+        let repo = MongoDB::new(db.clone());
+        let service = Service::new(MongoDB::new(db));
+        let breed = test_breed(&repo, "柯基").await;
+        let dog = service.create_dog("user-1", &test_dog(&breed, &[])).await.unwrap();
+        let update = DogUpdate {
+            name: Some("二二".to_owned()),
+            ..Default::default()
+        };
+        service.update_dog("user-1", &dog.id, &update, None).await.unwrap();
+        // 过期的版本号不能恢复
+        let e = service.revert_dog("user-2", &dog.id, 1, Some(1)).await.unwrap_err();
+        assert_eq!(e.kind(), crate::core::error::ErrorKind::PreconditionFailed);
+        let reverted = service.revert_dog("user-2", &dog.id, 1, Some(2)).await.unwrap();
+        assert_eq!((reverted.name.as_str(), reverted.version), ("不二", 3));
+        let versions = service.dog_versions(&dog.id).await.unwrap();
+        assert_eq!(versions.iter().map(|v| v.version).collect::<Vec<_>>(), [3, 2, 1]);
+        assert_eq!((versions[0].dog.name.as_str(), versions[0].actor.as_deref()), ("不二", Some("user-2")));
+        let audit = dog_audit(&repo, &dog.id).await;
+        assert_eq!(audit[0].changes.iter().map(|c| c.field.as_str()).collect::<Vec<_>>(), ["name"]);
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn update_dog_checks_components_against_the_stored_unknown_mix() {
+        let db = test_database().await;
+        let repo = MongoDB::new(db.clone());
+        let service = Service::new(MongoDB::new(db));
+        let (a, b) = (test_breed(&repo, "柯基").await, test_breed(&repo, "柴犬").await);
+        let dog = service.create_dog("user-1", &test_dog(&a, &[])).await.unwrap();
+        let components = serde_json::from_value::<Vec<crate::core::repository::BreedComponentCreate>>(json!([
+            { "breed": { "id": &a.id }, "percentage": 50 },
+            { "breed": { "id": &b.id }, "percentage": 20 },
+        ]))
+        .unwrap();
+        let update = DogUpdate {
+            breed_components: Some(components),
+            ..Default::default()
+        };
+        let e = service.update_dog("user-1", &dog.id, &update, None).await.unwrap_err();
+        assert_eq!(e.kind(), crate::core::error::ErrorKind::InvalidInput);
+        let mix = DogUpdate {
+            unknown_mix: Some(true),
+            ..Default::default()
+        };
+        assert!(service.update_dog("user-1", &dog.id, &mix, None).await.unwrap());
+        assert!(service.update_dog("user-1", &dog.id, &update, None).await.unwrap());
+        // 组成不足100%时不能取消"血统不明"
+        let e = service
+            .update_dog(
+                "user-1",
+                &dog.id,
+                &DogUpdate {
+                    unknown_mix: Some(false),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), crate::core::error::ErrorKind::InvalidInput);
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn update_breed_keeps_a_single_level_hierarchy() {
+        let db = test_database().await;
+        let repo = MongoDB::new(db.clone());
+        let service = Service::new(MongoDB::new(db));
+        let (poodle, toy, corgi) = (
+            test_breed(&repo, "贵宾").await,
+            test_breed(&repo, "玩具贵宾").await,
+            test_breed(&repo, "柯基").await,
+        );
+        let parent = |id: Option<&str>| BreedUpdate {
+            parent_id: Some(id.map(str::to_owned)),
+            ..Default::default()
+        };
+        let invalid = |res: Result<bool, Error>| res.unwrap_err().kind() == crate::core::error::ErrorKind::InvalidInput;
+        assert!(service.update_breed("admin", &toy.id, &parent(Some(&poodle.id)), None).await.unwrap());
+        assert_eq!(service.breed(&toy.id).await.unwrap().parent_id.as_deref(), Some(poodle.id.as_str()));
+        assert!(invalid(service.update_breed("admin", &toy.id, &parent(Some(&toy.id)), None).await));
+        // 子品种不能作为上级, 有子品种的品种也不能成为子品种
+        assert!(invalid(service.update_breed("admin", &corgi.id, &parent(Some(&toy.id)), None).await));
+        assert!(invalid(service.update_breed("admin", &poodle.id, &parent(Some(&corgi.id)), None).await));
+        // null清除上级品种
+        assert!(service.update_breed("admin", &toy.id, &parent(None), None).await.unwrap());
+        assert_eq!(service.breed(&toy.id).await.unwrap().parent_id, None);
+        assert!(service.update_breed("admin", &poodle.id, &parent(Some(&corgi.id)), None).await.unwrap());
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn update_breed_refreshes_snapshots_on_dogs() {
+        let db = test_database().await;
+        let repo = MongoDB::new(db);
+        let breed = test_breed(&repo, "柯基").await;
+        let dog = repo.create_dog("user-1", &test_dog(&breed, &[&breed])).await.unwrap();
+        let update = BreedUpdate {
+            name: Some("威尔士柯基".to_owned()),
+            ..Default::default()
+        };
+        assert!(repo.update_breed("admin", &breed.id, &update, Some(1)).await.unwrap());
+        let updated = repo
+            .query_dogs(&DogQuery {
+                id: Some(dog.id.clone()),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(updated.breed.name, "威尔士柯基");
+        assert_eq!(updated.breed_components[0].breed.name, "威尔士柯基");
+        assert_eq!(updated.version, 2);
+        // 只修改快照以外的字段时狗狗不变
+        let update = BreedUpdate {
+            aliases: Some(vec!["短腿".to_owned()]),
+            ..Default::default()
+        };
+        assert!(repo.update_breed("admin", &breed.id, &update, None).await.unwrap());
+        let unchanged = repo
+            .query_dogs(&DogQuery {
+                id: Some(dog.id.clone()),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(unchanged.version, 2);
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn merge_breeds_repoints_each_dog_once_in_one_transaction() {
+        let db = test_database().await;
+        let repo = MongoDB::new(db);
+        let (from, to) = (test_breed(&repo, "威尔士柯基").await, test_breed(&repo, "柯基").await);
+        // 主品种和混血组成都引用from, 只修改一次
+        let dog = repo.create_dog("user-1", &test_dog(&from, &[&from, &to])).await.unwrap();
+        assert_eq!(repo.merge_breeds("admin", &from.id, &to.id).await.unwrap(), Some(1));
+        let repointed = repo
+            .query_dogs(&DogQuery {
+                id: Some(dog.id.clone()),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(repointed.version, 2);
+        assert_eq!(repointed.breed.id, to.id);
+        assert!(repointed.breed_components.iter().all(|c| c.breed.id == to.id));
+        assert!(repointed.seq > dog.seq);
+        assert_eq!(
+            repo.dog_versions(&dog.id).await.unwrap().iter().map(|v| v.version).collect::<Vec<_>>(),
+            [2, 1]
+        );
+        let audit = dog_audit(&repo, &dog.id).await;
+        assert_eq!((audit[0].action, audit[0].actor.as_str()), (AuditAction::Update, "admin"));
+        assert_eq!(
+            audit[0].changes.iter().map(|c| c.field.as_str()).collect::<Vec<_>>(),
+            ["breed", "breed_components"]
+        );
+        let (breeds, _) = repo.query_breeds(&BreedQuery::default()).await.unwrap();
+        assert_eq!(breeds.len(), 1);
+        assert_eq!(
+            (breeds[0].aliases.as_slice(), breeds[0].version),
+            (["威尔士柯基".to_owned()].as_slice(), 2)
+        );
+        let merged = repo
+            .query_audit(&AuditQuery {
+                entity: Some(AuditEntity::Breed),
+                entity_id: Some(from.id.clone()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(merged[0].action, AuditAction::Merge);
+        // 已经合并的品种不存在
+        assert_eq!(repo.merge_breeds("admin", &from.id, &to.id).await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn deleted_dogs_are_hidden_until_restored_by_the_owner() {
+        let db = test_database().await;
+        let repo = MongoDB::new(db.clone());
+        let service = Service::new(MongoDB::new(db));
+        let breed = test_breed(&repo, "柯基").await;
+        let dog = service.create_dog("user-1", &test_dog(&breed, &[])).await.unwrap();
+        // 过期的版本号不能删除
+        let e = service.delete_dog("user-1", &dog.id, Some(2)).await.unwrap_err();
+        assert_eq!(e.kind(), crate::core::error::ErrorKind::PreconditionFailed);
+        service.delete_dog("user-1", &dog.id, Some(1)).await.unwrap();
+        assert_eq!(service.dog(&dog.id).await.unwrap_err().kind(), crate::core::error::ErrorKind::NotFound);
+        assert_eq!(
+            service.delete_dog("user-1", &dog.id, None).await.unwrap_err().kind(),
+            crate::core::error::ErrorKind::NotFound
+        );
+        // 只有主人可以恢复
+        let e = service.restore_dog("user-2", &dog.id, "user-2").await.unwrap_err();
+        assert_eq!(e.kind(), crate::core::error::ErrorKind::NotFound);
+        let restored = service.restore_dog("user-1", &dog.id, "user-1").await.unwrap();
+        assert_eq!((restored.version, restored.name.as_str()), (3, "不二"));
+        let audit = dog_audit(&repo, &dog.id).await;
+        assert_eq!(
+            audit.iter().map(|a| a.action).collect::<Vec<_>>(),
+            [AuditAction::Restore, AuditAction::Delete, AuditAction::Create]
+        );
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn dogs_deleted_outside_the_restore_window_cannot_be_restored() {
+        let db = test_database().await;
+        let repo = MongoDB::new(db.clone());
+        let service = Service::new(MongoDB::new(db)).with_restore_window(chrono::Duration::zero());
+        let breed = test_breed(&repo, "柯基").await;
+        let dog = service.create_dog("user-1", &test_dog(&breed, &[])).await.unwrap();
+        service.delete_dog("user-1", &dog.id, None).await.unwrap();
+        let e = service.restore_dog("user-1", &dog.id, "user-1").await.unwrap_err();
+        assert_eq!(e.kind(), crate::core::error::ErrorKind::NotFound);
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn purge_dogs_removes_expired_dogs_with_their_versions() {
+        let db = test_database().await;
+        let repo = MongoDB::new(db.clone());
+        let service = Service::new(MongoDB::new(db)).with_restore_window(chrono::Duration::zero());
+        let breed = test_breed(&repo, "柯基").await;
+        let deleted = service.create_dog("user-1", &test_dog(&breed, &[])).await.unwrap();
+        let live = service.create_dog("user-1", &test_dog(&breed, &[])).await.unwrap();
+        service.delete_dog("user-1", &deleted.id, None).await.unwrap();
+        // 保留期内不删除
+        assert_eq!(service.purge_dogs(chrono::Duration::days(1)).await.unwrap(), 0);
+        assert_eq!(service.purge_dogs(chrono::Duration::zero()).await.unwrap(), 1);
+        assert!(repo.dog_versions(&deleted.id).await.unwrap().is_empty());
+        assert_eq!(repo.dog_versions(&live.id).await.unwrap().len(), 1);
+        assert_eq!(service.dog(&live.id).await.unwrap().version, 1);
+        assert_eq!(service.purge_dogs(chrono::Duration::zero()).await.unwrap(), 0);
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn claimed_events_are_not_claimed_again_until_the_lease_expires() {
+        let repo = MongoDB::new(test_database().await);
+        let breed = test_breed(&repo, "柯基").await;
+        repo.create_dog("user-1", &test_dog(&breed, &[])).await.unwrap();
+        let (now, lease) = (Utc::now(), chrono::Duration::minutes(1));
+        let events = repo.claim_events(now, lease, 10).await.unwrap();
+        assert_eq!(
+            events.iter().map(|e| e.event_type).collect::<Vec<_>>(),
+            [EventType::BreedChanged, EventType::DogCreated]
+        );
+        assert!(repo.claim_events(now, lease, 10).await.unwrap().is_empty());
+        // 占用过期后重新占用
+        let events = repo.claim_events(now + lease, lease, 1).await.unwrap();
+        assert_eq!(events.len(), 1);
+        repo.mark_events_dispatched(&[events[0].id.clone()]).await.unwrap();
+        let events = repo.claim_events(now + lease * 3, lease, 10).await.unwrap();
+        assert_eq!(events.iter().map(|e| e.event_type).collect::<Vec<_>>(), [EventType::DogCreated]);
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn transfer_dog_emits_a_single_transferred_event() {
+        let db = test_database().await;
+        let repo = MongoDB::new(db.clone());
+        let service = Service::new(MongoDB::new(db));
+        let breed = test_breed(&repo, "柯基").await;
+        let dog = service.create_dog("user-1", &test_dog(&breed, &[])).await.unwrap();
+        let (now, lease) = (Utc::now(), chrono::Duration::minutes(1));
+        repo.claim_events(now, lease, 10).await.unwrap();
+        let transferred = service.transfer_dog("user-1", &dog.id, "user-1", "user-2", Some(1)).await.unwrap();
+        assert_eq!((transferred.owner_id.as_str(), transferred.version), ("user-2", 2));
+        let events = repo.claim_events(now, lease, 10).await.unwrap();
+        assert_eq!(events.iter().map(|e| e.event_type).collect::<Vec<_>>(), [EventType::DogTransferred]);
+        assert_eq!((events[0].owner_id.as_deref(), events[0].version), (Some("user-2"), 2));
+        assert_eq!((&events[0].data["from"], &events[0].data["to"]), (&json!("user-1"), &json!("user-2")));
+    }
+
+    #[actix_web::test]
+    #[ignore]
+    async fn atomic_batch_update_reports_versions_read_in_the_transaction() {
+        use crate::core::batch::{BatchItem, BatchStatus};
+
+        let db = test_database().await;
+        let repo = MongoDB::new(db.clone());
+        let service = Service::new(MongoDB::new(db));
+        let breed = test_breed(&repo, "柯基").await;
+        let (a, b) = (
+            service.create_dog("user-1", &test_dog(&breed, &[])).await.unwrap(),
+            service.create_dog("user-1", &test_dog(&breed, &[])).await.unwrap(),
+        );
+        let item = |index: usize, id: &str, version: u64, name: Option<&str>| BatchItem {
+            index,
+            item: Ok(DogBatchUpdate {
+                id: id.to_owned(),
+                version: Some(version),
+                dog: DogUpdate {
+                    name: name.map(str::to_owned),
+                    ..Default::default()
+                },
+            }),
+        };
+        let report = service
+            .update_dogs("user-1", vec![item(0, &a.id, 1, Some("二二")), item(1, &b.id, 1, None)], true)
+            .await
+            .unwrap();
+        assert_eq!(
+            report.items.iter().map(|i| (i.status, i.version)).collect::<Vec<_>>(),
+            [(BatchStatus::Updated, Some(2)), (BatchStatus::Unchanged, Some(1))]
+        );
+        // 任一项版本号过期时都不修改
+        let report = service
+            .update_dogs("user-1", vec![item(0, &a.id, 2, Some("三三")), item(1, &b.id, 2, Some("三三"))], true)
+            .await
+            .unwrap();
+        assert_eq!(
+            report.items.iter().map(|i| i.status).collect::<Vec<_>>(),
+            [BatchStatus::Skipped, BatchStatus::Failed]
+        );
+        assert_eq!(service.dog(&a.id).await.unwrap().name, "二二");
+        assert_eq!(service.dog(&b.id).await.unwrap().name, "不二");
+    }
+}